@@ -1,9 +1,39 @@
-use std::{cell::RefCell, io::{Result, Read, BufRead, BufReader}};
-use std::convert::TryInto;
+use std::{cell::RefCell, io::Read};
 
+use candid::{CandidType, Deserialize};
 use fatfs::{Write, Seek, SeekFrom};
+use ic_cdk::api::stable::{stable64_read, stable64_size};
 use ic_cdk_macros::{query, update};
 
+mod acl;
+mod copy;
+mod download;
+mod error;
+mod fat;
+mod fsck;
+mod glob;
+mod hash;
+mod home;
+mod journal;
+mod path;
+mod search;
+mod snapshot;
+mod stat;
+mod system;
+mod tar;
+mod text;
+mod timestamp;
+mod trash;
+mod tree;
+mod upload;
+mod version;
+mod volume;
+mod zip_archive;
+
+use acl::Permission;
+pub use acl::remember_controller;
+use error::{FsError, FsResult};
+
 type FileSystem = fatfs::FileSystem<
     fatfs::StdIoWrapper<icfs::StableMemory>,
     icfs_fatfs::TimeProvider,
@@ -17,242 +47,700 @@ type Dir<'a> = fatfs::Dir<
     fatfs::LossyOemCpConverter,
 >;
 
+type DirEntry<'a> = fatfs::DirEntry<
+    'a,
+    fatfs::StdIoWrapper<icfs::StableMemory>,
+    icfs_fatfs::TimeProvider,
+    fatfs::LossyOemCpConverter,
+>;
+
+type File<'a> = fatfs::File<
+    'a,
+    fatfs::StdIoWrapper<icfs::StableMemory>,
+    icfs_fatfs::TimeProvider,
+    fatfs::LossyOemCpConverter,
+>;
+
+enum FsState {
+    // Stable memory holds no formatted volume yet.
+    Uninitialized,
+    // Explicitly unmounted; stays that way until `mount` is called.
+    Unmounted,
+    Mounted(FileSystem),
+    // A boot sector is present but fatfs refused the volume.
+    Corrupt(String),
+}
+
+impl FsState {
+    fn mount() -> Self {
+        match open_volume() {
+            Ok(fs) => FsState::Mounted(fs),
+            Err(FsError::NotInitialized) => FsState::Uninitialized,
+            Err(FsError::Io(reason)) => FsState::Corrupt(reason),
+            Err(error) => FsState::Corrupt(format!("{:?}", error)),
+        }
+    }
+
+    fn error(&self) -> FsError {
+        match self {
+            FsState::Corrupt(reason) => FsError::Io(format!("Volume is corrupt: {}", reason)),
+            _ => FsError::NotInitialized,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum VolumeStatus {
+    Uninitialized,
+    Unmounted,
+    Mounted,
+    Corrupt(String),
+}
+
+/// A modification of the volume. Metadata kept about paths, such as the hash
+/// index, follows along through `record`.
+pub enum Change {
+    Written(Vec<String>),
+    Created(Vec<String>),
+    Removed(Vec<String>),
+    Renamed(Vec<String>, Vec<String>),
+}
+
+/// Brings the metadata kept about paths up to date after `changes` were made.
+/// Metadata that missed a change would no longer match the data, so a
+/// failure traps, which rolls back the whole call including the change.
+fn record(fs: &FileSystem, changes: &[Change]) {
+    let result = hash::apply(fs, changes)
+        .and_then(|()| acl::apply(fs, changes))
+        .and_then(|()| version::apply(fs, changes))
+        .and_then(|()| journal::apply(fs, changes))
+        .and_then(|()| home::apply(fs, changes));
+    if let Err(error) = result {
+        ic_cdk::trap(&format!("Failed to update metadata: {:?}", error));
+    }
+}
+
 thread_local! {
     static STABLE_MEMORY: RefCell<icfs::StableMemory> = RefCell::new(icfs::StableMemory::default());
-    static FS: RefCell<FileSystem> = {
-        let fs: Result<FileSystem> = STABLE_MEMORY.with(|stable_memory| {
-            let stable_memory = *stable_memory.borrow();
+    static FS: RefCell<FsState> = RefCell::new(FsState::mount());
+}
 
-            let options = fatfs::FsOptions::new()
-                .time_provider(icfs_fatfs::TimeProvider::new())
-                .update_accessed_date(true);
+fn open_volume() -> FsResult<FileSystem> {
+    if stable64_size() == 0 {
+        return Err(FsError::NotInitialized);
+    }
 
-            let fs = fatfs::FileSystem::new(stable_memory, options)?;
-            Ok(fs)
-        });
-        RefCell::new(fs.unwrap())
-    };
+    // Without the boot sector signature there is nothing to mount yet.
+    let mut signature = [0; 2];
+    stable64_read(510, &mut signature);
+    if signature != [0x55, 0xAA] {
+        return Err(FsError::NotInitialized);
+    }
+
+    STABLE_MEMORY.with(|stable_memory| {
+        let stable_memory = *stable_memory.borrow();
+
+        let options = fatfs::FsOptions::new()
+            .time_provider(icfs_fatfs::TimeProvider::new())
+            .update_accessed_date(true);
+
+        let fs = fatfs::FileSystem::new(stable_memory, options)?;
+        Ok(fs)
+    })
 }
 
+fn is_mounted() -> bool {
+    FS.with(|state| matches!(*state.borrow(), FsState::Mounted(_)))
+}
+
+fn with_fs<T>(f: impl FnOnce(&FileSystem) -> FsResult<T>) -> FsResult<T> {
+    let result = FS.with(|state| match &*state.borrow() {
+        FsState::Mounted(fs) => f(fs),
+        state => Err(state.error()),
+    });
+    home::settle();
+    result
+}
+
+/// Unmounts the filesystem while `f` works on the raw volume and mounts it
+/// again afterwards.
+fn with_unmounted<T>(f: impl FnOnce() -> FsResult<T>) -> FsResult<T> {
+    FS.with(|state| {
+        if let FsState::Mounted(fs) = state.replace(FsState::Unmounted) {
+            acl::remember_volume_admins(&fs);
+            home::forget_usage();
+            if let Err(error) = fs.unmount() {
+                state.replace(FsState::mount());
+                return Err(error.into());
+            }
+        }
+
+        let result = f();
+        state.replace(FsState::mount());
+        result
+    })
+}
+
+/// Mounts the volume in stable memory unless it is mounted already.
+fn mount_volume() -> FsResult<()> {
+    FS.with(|state| {
+        let mut state = state.borrow_mut();
+        if !matches!(*state, FsState::Mounted(_)) {
+            *state = FsState::mount();
+        }
+
+        match &*state {
+            FsState::Mounted(_) => Ok(()),
+            state => Err(state.error()),
+        }
+    })
+}
+
+/// Mounts the volume in stable memory. This happens on first use anyway;
+/// it is needed after `unmount` or to retry a volume found to be corrupt.
 #[update]
-fn init_volume(confirm: Option<String>) -> Option<&'static str> {
-    match confirm {
-        None => Some("confirm to init_volume ? (input confirm_to_init_volume to init.)"),
-        Some(s) => if s == "confirm_to_init_volume" {
-            STABLE_MEMORY.with(|stable_memory| {
-                #[cfg(target_arch = "wasm32")]
-                let memory_pages = core::arch::wasm32::memory_size(0)
-                    .try_into()
-                    .map_err(|error| std::io::Error::new(std::io::ErrorKind::Other, error)).unwrap();
-    
-                #[cfg(not(target_arch = "wasm32"))]
-                let memory_pages = 19;
-    
-                icfs::StableMemory::grow(memory_pages).unwrap();
-
-                fatfs::format_volume(
-                    &mut fatfs::StdIoWrapper::from(*stable_memory.borrow()),
-                     fatfs::FormatVolumeOptions::new()).unwrap();
-            });
-            Some("init_volume completed")
-        } else {
-            Some("confirm to init_volume ? (input confirm_to_init_volume to init.)")
-        },
-    }
+fn mount() -> FsResult<()> {
+    acl::check_volume()?;
+    mount_volume()
+}
+
+/// Flushes and unmounts the volume. Endpoints fail with `NotInitialized`
+/// until `mount` is called again.
+pub fn unmount_volume() -> FsResult<()> {
+    FS.with(|state| match state.replace(FsState::Unmounted) {
+        FsState::Mounted(fs) => {
+            acl::remember_volume_admins(&fs);
+            home::forget_usage();
+            Ok(fs.unmount()?)
+        }
+        _ => Ok(()),
+    })
+}
+
+#[update]
+fn unmount() -> FsResult<()> {
+    acl::check_volume()?;
+    unmount_volume()
+}
+
+#[query]
+fn volume_status() -> VolumeStatus {
+    FS.with(|state| match &*state.borrow() {
+        FsState::Uninitialized => VolumeStatus::Uninitialized,
+        FsState::Unmounted => VolumeStatus::Unmounted,
+        FsState::Mounted(_) => VolumeStatus::Mounted,
+        FsState::Corrupt(reason) => VolumeStatus::Corrupt(reason.clone()),
+    })
+}
+
+fn open_dir_components<'a>(fs: &'a FileSystem, components: &[String]) -> FsResult<Dir<'a>> {
+    components
+        .iter()
+        .try_fold(fs.root_dir(), |dir, name| open_dir(&dir, name))
+}
+
+/// Normalizes `path` and spells every component that names an existing
+/// entry the way the entry's long name is, so that an 8.3 alias or another
+/// case can't slip past metadata kept by path, such as ACLs, quotas,
+/// versions, leases, hashes and the journal.
+fn canonical(fs: &FileSystem, path: &str) -> FsResult<Vec<String>> {
+    canonicalize(fs, path::normalize(path)?)
+}
+
+/// Like `canonical`, for components that are normalized already.
+fn canonicalize(fs: &FileSystem, components: Vec<String>) -> FsResult<Vec<String>> {
+    let mut dir = Some(fs.root_dir());
+    path::canonicalize(components, |_, name| {
+        let entry = match dir.take() {
+            Some(dir) => find_entry(&dir, name)?,
+            None => return Ok(None),
+        };
+        Ok(entry.map(|entry| {
+            if entry.is_dir() {
+                dir = Some(entry.to_dir());
+            }
+            entry.file_name()
+        }))
+    })
 }
 
-fn open_dir_path<'a>(fs: &'a FileSystem, path: &str) -> std::io::Result<Dir<'a>> {
-    let root_dir = fs.root_dir();
-    let (base_dir_name, sub_dir_path) = path_head_tail(&path)
-        .map_err(|error| std::io::Error::new(std::io::ErrorKind::Other, error))?;
-    
-    match (base_dir_name.as_str(), sub_dir_path.as_str()) {
-        (".", "") => Ok(root_dir),
-        (".", sub_dir_path) => root_dir
-            .open_dir(&sub_dir_path)
-            .map_err(|error| std::io::Error::new(std::io::ErrorKind::Other, error)),
-        _ => Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("Invalid path: {}", path.to_string()),
-        )),
-    }   
+/// Opens the directory containing `path` and returns it with the final path component.
+fn open_parent<'a>(fs: &'a FileSystem, path: &str) -> FsResult<(Dir<'a>, String)> {
+    let (dir_path, name) = path::split_last(path)?;
+    let dir = open_dir_components(fs, &dir_path)?;
+    Ok((dir, name))
+}
 
+// FAT names compare case-insensitively, the same way fatfs matches them.
+fn eq_name(a: &str, b: &str) -> bool {
+    a.chars()
+        .flat_map(char::to_uppercase)
+        .eq(b.chars().flat_map(char::to_uppercase))
 }
 
-fn path_head_tail(path: &str) -> core::result::Result<(String, String), String> {
-    let path_segments = path.split("/").collect::<Vec<_>>();
-    let head = path_segments
-        .first()
-        .ok_or(format!("Invalid path: {}", path.to_string()))?;
+fn find_entry<'a>(dir: &Dir<'a>, name: &str) -> FsResult<Option<DirEntry<'a>>> {
+    for entry in dir.iter() {
+        let entry = entry?;
+        let long_name = entry.file_name();
+        if eq_name(&long_name, name) {
+            return Ok(Some(entry));
+        }
+        if eq_name(&entry.short_file_name(), name) {
+            // The system directory may only be reached by its long name, or
+            // its alias would get past `path::is_system`.
+            if eq_name(&long_name, path::SYSTEM_DIR) {
+                return Err(FsError::InvalidPath);
+            }
+            return Ok(Some(entry));
+        }
+    }
+    Ok(None)
+}
 
-    let tail = path_segments[1..].join("/");
-    Ok((head.to_string(), tail))
+fn open_dir<'a>(dir: &Dir<'a>, name: &str) -> FsResult<Dir<'a>> {
+    match find_entry(dir, name)? {
+        Some(entry) if entry.is_dir() => Ok(entry.to_dir()),
+        Some(_) => Err(FsError::NotADirectory),
+        None => Err(FsError::NotFound),
+    }
 }
 
-fn path_init_last(path: &str) -> core::result::Result<(String, String), String>  {
-    let mut path_segments = path.split("/").collect::<Vec<_>>();
-    let last = path_segments
-        .pop()
-        .ok_or(format!("Invalid path: {}", path.to_string()))?;
-    
-    let init = path_segments.join("/");
-    Ok((init, last.to_string()))
+fn open_file<'a>(dir: &Dir<'a>, name: &str) -> FsResult<File<'a>> {
+    match find_entry(dir, name)? {
+        Some(entry) if entry.is_dir() => Err(FsError::IsADirectory),
+        Some(entry) => Ok(entry.to_file()),
+        None => Err(FsError::NotFound),
+    }
+}
+
+/// Opens `name` for writing, creating it if it doesn't exist yet.
+fn create_file<'a>(dir: &Dir<'a>, name: &str) -> FsResult<File<'a>> {
+    match find_entry(dir, name)? {
+        Some(entry) if entry.is_dir() => Err(FsError::IsADirectory),
+        Some(entry) => Ok(entry.to_file()),
+        None => Ok(dir.create_file(name)?),
+    }
 }
 
 #[query]
-fn cat(path: String) -> String {
-    FS.with(|fs| {
-        let fs = fs.borrow();
-        let (dir_path, file_name) = path_init_last(&path)
-            .map_err(|error| std::io::Error::new(std::io::ErrorKind::Other, error))?;
-        let dir = open_dir_path(&fs, &dir_path)?;
-        let mut file = dir.open_file(&file_name)?;
+fn cat(path: String) -> FsResult<String> {
+    with_fs(|fs| {
+        acl::check_path(fs, &path, Permission::Read)?;
+        let (dir, file_name) = open_parent(fs, &path)?;
+        let mut file = open_file(&dir, &file_name)?;
         let mut buf = vec![];
         file.read_to_end(&mut buf)?;
 
-        let contents = String::from_utf8(buf)
-            .map_err(|error| std::io::Error::new(std::io::ErrorKind::Other, error))?;
-        
-        std::io::Result::Ok(contents)
+        String::from_utf8(buf).map_err(|error| FsError::Io(error.to_string()))
     })
-    .unwrap()
 }
 
 #[query]
-fn read_root_size() -> Vec<u64> {
-    FS.with(|fs| {
-        let fs = fs.borrow();
-
-        let root_dir = fs.root_dir();
+fn read_bytes(path: String) -> FsResult<serde_bytes::ByteBuf> {
+    with_fs(|fs| {
+        acl::check_path(fs, &path, Permission::Read)?;
+        let (dir, file_name) = open_parent(fs, &path)?;
+        let mut file = open_file(&dir, &file_name)?;
+        let mut buf = vec![];
+        file.read_to_end(&mut buf)?;
 
-        let a = root_dir.iter().map(|d| {
-            d.unwrap().len()
-        }).collect::<Vec<u64>>();
- 
-        std::io::Result::Ok(a)
+        Ok(serde_bytes::ByteBuf::from(buf))
     })
-    .unwrap()
 }
 
 #[query]
-fn read_lines(path: String) -> Vec<String> {
-    FS.with(|fs| {
-        let fs = fs.borrow();
-        let (dir_path, file_name) = path_init_last(&path)
-            .map_err(|error| std::io::Error::new(std::io::ErrorKind::Other, error))?;
-        
-        let dir = open_dir_path(&fs, &dir_path)?;
-        let mut file = dir.open_file(&file_name)?;
-        let v = BufReader::new(&mut file).lines();
-        let lines = v.map(|s| s.unwrap()).collect::<Vec<String>>();
-
-        std::io::Result::Ok(lines)
+fn read_root_size() -> FsResult<Vec<u64>> {
+    with_fs(|fs| {
+        acl::check(fs, &[], Permission::Read)?;
+        let root_dir = fs.root_dir();
+
+        let mut sizes = vec![];
+        for entry in root_dir.iter() {
+            let entry = entry?;
+            if !path::is_system(&[], &entry.file_name()) {
+                sizes.push(entry.len());
+            }
+        }
+        Ok(sizes)
     })
-    .unwrap()
 }
 
 #[query]
-fn cat_at(path: String, at: u64) -> String {
-    FS.with(|fs| {
-        let fs = fs.borrow();
-        let (dir_path, file_name) = path_init_last(&path)
-            .map_err(|error| std::io::Error::new(std::io::ErrorKind::Other, error))?;
-        let dir = open_dir_path(&fs, &dir_path)?;
-
-        let mut file = dir.open_file(&file_name)?;
-        file.seek(SeekFrom::Start(at)).unwrap();
+fn read_range(path: String, offset: u64, len: u64) -> FsResult<serde_bytes::ByteBuf> {
+    with_fs(|fs| {
+        acl::check_path(fs, &path, Permission::Read)?;
+        let len = len.min(text::max_response_size(fs)?);
+        let (dir, file_name) = open_parent(fs, &path)?;
+        let mut file = open_file(&dir, &file_name)?;
+        file.seek(SeekFrom::Start(offset))?;
 
         let mut buf = vec![];
-        file.read_to_end(&mut buf)?;
-
-        let contents = String::from_utf8(buf)
-            .map_err(|error| std::io::Error::new(std::io::ErrorKind::Other, error))?;
-        std::io::Result::Ok(contents)
+        (&mut file).take(len).read_to_end(&mut buf)?;
+        Ok(serde_bytes::ByteBuf::from(buf))
     })
-    .unwrap()
 }
 
 #[query]
-fn ls(path: String) -> Vec<String> {
-    FS.with(|fs| {
-        let fs = fs.borrow();
-        let dir = open_dir_path(&fs, &path)?;
+fn ls(path: String) -> FsResult<Vec<String>> {
+    with_fs(|fs| {
+        acl::check_path(fs, &path, Permission::Read)?;
+        let components = canonical(fs, &path)?;
+        let dir = open_dir_components(fs, &components)?;
         let mut entries = dir
             .iter()
-            .map(|entry| {
-                entry.map(|e| e.file_name())
-                .map_err(|error| std::io::Error::new(std::io::ErrorKind::Other, error))
-            })
-            .collect::<std::io::Result<Vec<String>>>();
-
-        match entries.as_mut() {
-            Ok(ok) => ok.sort(),
-            Err(_err) => (),
-        }
+            .map(|entry| Ok(entry?.file_name()))
+            .filter(|name| !matches!(name, Ok(name) if path::is_system(&components, name)))
+            .collect::<FsResult<Vec<String>>>()?;
 
-        entries
+        entries.sort();
+        Ok(entries)
     })
-    .unwrap()
 }
 
 #[update]
-fn mkdir(path: String) {
-    FS.with(|fs| {
-        let fs = fs.borrow();
-        let (dir_path, dir_name) = path_init_last(&path)
-            .map_err(|error| std::io::Error::new(std::io::ErrorKind::Other, error))?;
-        
-        let dir = open_dir_path(&fs, &dir_path)?;
+fn mkdir(path: String) -> FsResult<()> {
+    with_fs(|fs| {
+        let components = canonical(fs, &path)?;
+        acl::check(fs, &components, Permission::Write)?;
+        home::ensure(fs, &components)?;
+        let (dir, dir_name) = open_parent(fs, &path)?;
+        if find_entry(&dir, &dir_name)?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        home::reserve_dir(fs, &components)?;
         dir.create_dir(&dir_name)?;
-        std::io::Result::Ok(())
+        record(fs, &[Change::Created(components)]);
+        Ok(())
+    })
+}
+
+// Fails with `DirectoryNotEmpty` for a directory that holds anything.
+fn check_empty(entry: &DirEntry) -> FsResult<()> {
+    if entry.is_dir() {
+        for child in entry.to_dir().iter() {
+            if !matches!(child?.file_name().as_str(), "." | "..") {
+                return Err(FsError::DirectoryNotEmpty);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Moves `path` into the trash, see `trash_restore` and `trash::discard`.
+/// Directories have to be empty, as with a plain remove; `remove_all` takes
+/// whole trees.
+#[update]
+fn rm(path: String) -> FsResult<()> {
+    with_fs(|fs| {
+        acl::check_path(fs, &path, Permission::Write)?;
+        let components = canonical(fs, &path)?;
+        let (dir, target) = open_parent(fs, &path)?;
+        check_empty(&find_entry(&dir, &target)?.ok_or(FsError::NotFound)?)?;
+        trash::discard(fs, &dir, &target, &components)?;
+        record(fs, &[Change::Removed(components)]);
+        Ok(())
+    })
+}
+
+fn starts_with_path(path: &[String], prefix: &[String]) -> bool {
+    path.len() >= prefix.len() && path.iter().zip(prefix).all(|(a, b)| eq_name(a, b))
+}
+
+/// Moves `from` to `to`, possibly into another directory. An existing entry
+/// at `to` of the same kind is replaced only when `overwrite` is set, and a
+/// directory is only replaced when it is empty. Replaced entries go to the
+/// trash.
+#[update]
+fn rename(from: String, to: String, overwrite: bool) -> FsResult<()> {
+    with_fs(|fs| {
+        let from_components = canonical(fs, &from)?;
+        let to_components = canonical(fs, &to)?;
+        if from_components.is_empty() || to_components.is_empty() {
+            return Err(FsError::InvalidPath);
+        }
+        acl::check_tree(fs, &from_components, Permission::Write)?;
+        acl::check(fs, &to_components, Permission::Write)?;
+        home::ensure(fs, &to_components)?;
+
+        let (src_dir, src_name) = open_parent(fs, &from)?;
+        let source = find_entry(&src_dir, &src_name)?.ok_or(FsError::NotFound)?;
+        let (dst_dir, dst_name) = open_parent(fs, &to)?;
+
+        if starts_with_path(&to_components, &from_components) {
+            if to_components.len() > from_components.len() {
+                // A directory can't be moved into its own subtree.
+                return Err(FsError::InvalidPath);
+            }
+
+            // Same entry, at most the case of its name changes. fatfs refuses
+            // to rename onto an existing name, so go through a temporary one.
+            if source.file_name() != dst_name {
+                let temp_name = format!("{}.~rename", dst_name);
+                src_dir.rename(&src_name, &src_dir, &temp_name)?;
+                src_dir.rename(&temp_name, &src_dir, &dst_name)?;
+            }
+            record(fs, &[Change::Renamed(from_components, to_components)]);
+            return Ok(());
+        }
+
+        home::reserve_tree(fs, Some(&from_components), &to_components, &source)?;
+        let mut changes = vec![];
+        if let Some(existing) = find_entry(&dst_dir, &dst_name)? {
+            if !overwrite {
+                return Err(FsError::AlreadyExists);
+            }
+            match (source.is_dir(), existing.is_dir()) {
+                (true, false) => return Err(FsError::NotADirectory),
+                (false, true) => return Err(FsError::IsADirectory),
+                _ => check_empty(&existing)?,
+            }
+            trash::discard(fs, &dst_dir, &dst_name, &to_components)?;
+            changes.push(Change::Removed(to_components.clone()));
+        }
+
+        src_dir.rename(&src_name, &dst_dir, &dst_name)?;
+        changes.push(Change::Renamed(from_components, to_components));
+        record(fs, &changes);
+        Ok(())
+    })
+}
+
+#[update]
+fn write(path: String, contents: String) -> FsResult<()> {
+    with_fs(|fs| {
+        let components = canonical(fs, &path)?;
+        acl::check(fs, &components, Permission::Write)?;
+        home::ensure(fs, &components)?;
+        let (dir, file_name) = open_parent(fs, &path)?;
+        reserve_append(fs, &dir, &file_name, &components, contents.len() as u64)?;
+        let mut file = create_file(&dir, &file_name)?;
+
+        file.seek(SeekFrom::End(0))?;
+        write_or_trap(&components, || {
+            file.write_all(contents.as_bytes())?;
+            Ok(file.flush()?)
+        });
+        record(fs, &[Change::Written(components)]);
+        Ok(())
     })
-    .unwrap()
 }
 
 #[update]
-fn rm(path: String) {
-    FS.with(|fs| {
-        let fs = fs.borrow();
-        let (dir_path, target) = path_init_last(&path)
-            .map_err(|error| std::io::Error::new(std::io::ErrorKind::Other, error))?;
-        
-        let dir = open_dir_path(&fs, &dir_path)?;
-        dir.remove(&target)?;
-        std::io::Result::Ok(())
+fn write_file(path: String, contents: String) -> FsResult<()> {
+    with_fs(|fs| {
+        let components = canonical(fs, &path)?;
+        acl::check(fs, &components, Permission::Write)?;
+        home::ensure(fs, &components)?;
+        let (dir, file_name) = open_parent(fs, &path)?;
+        replace_file(fs, &dir, &file_name, &components, contents.as_bytes())?;
+        record(fs, &[Change::Written(components)]);
+        Ok(())
     })
-    .unwrap()
 }
 
 #[update]
-fn write(path: String, contents: String) {
-    FS.with(|fs| {
-        let fs = fs.borrow();
-        let (dir_path, file_name) = path_init_last(&path)
-            .map_err(|error| std::io::Error::new(std::io::ErrorKind::Other, error))?;
-        
-        let dir = open_dir_path(&fs, &dir_path)?;
-        let mut file = dir.create_file(&file_name)?;
-        
-        file.seek(SeekFrom::End(0)).unwrap();
-        file.write(&contents.into_bytes())?;
-        file.flush()?;
-        std::io::Result::Ok(())
+fn write_bytes(path: String, contents: serde_bytes::ByteBuf) -> FsResult<()> {
+    with_fs(|fs| {
+        let components = canonical(fs, &path)?;
+        acl::check(fs, &components, Permission::Write)?;
+        home::ensure(fs, &components)?;
+        let (dir, file_name) = open_parent(fs, &path)?;
+        replace_file(fs, &dir, &file_name, &components, &contents)?;
+        record(fs, &[Change::Written(components)]);
+        Ok(())
     })
-    .unwrap()
 }
 
 #[update]
-fn write_file(path: String, contents: String) {
-    FS.with(|fs| {
-        let fs = fs.borrow();
-        let (dir_path, file_name) = path_init_last(&path)
-            .map_err(|error| std::io::Error::new(std::io::ErrorKind::Other, error))?;
-        let dir = open_dir_path(&fs, &dir_path)?;
-        let mut file = dir.create_file(&file_name)?;
+fn append_bytes(path: String, contents: serde_bytes::ByteBuf) -> FsResult<()> {
+    with_fs(|fs| {
+        let components = canonical(fs, &path)?;
+        acl::check(fs, &components, Permission::Write)?;
+        home::ensure(fs, &components)?;
+        let (dir, file_name) = open_parent(fs, &path)?;
+        reserve_append(fs, &dir, &file_name, &components, contents.len() as u64)?;
+        let mut file = create_file(&dir, &file_name)?;
+        file.seek(SeekFrom::End(0))?;
+        write_or_trap(&components, || {
+            file.write_all(&contents)?;
+            Ok(file.flush()?)
+        });
+        record(fs, &[Change::Written(components)]);
+        Ok(())
+    })
+}
+
+// Runs `write`, which mutates the file at `components`. Returning an error
+// would commit whatever part of it was done, such as a truncated file, so a
+// failure traps and rolls the whole call back instead.
+fn write_or_trap(components: &[String], write: impl FnOnce() -> FsResult<()>) {
+    if let Err(error) = write() {
+        ic_cdk::trap(&format!(
+            "Failed to write {}: {:?}",
+            path::join(components),
+            error
+        ));
+    }
+}
+
+// Checks the home quota and the free space before `added` bytes are appended
+// to the file `name` in `dir`, which is created if it doesn't exist yet.
+fn reserve_append(
+    fs: &FileSystem,
+    dir: &Dir,
+    name: &str,
+    components: &[String],
+    added: u64,
+) -> FsResult<()> {
+    let size = find_entry(dir, name)?.map_or(0, |entry| entry.len());
+    let len = size
+        .checked_add(added)
+        .ok_or_else(|| FsError::InvalidArgument("File too large".to_string()))?;
+    check_growth(fs, size, len)?;
+    home::reserve_file(fs, components, len)
+}
+
+// Replaces the contents of the file `name` in `dir`, at `components`, with
+// `contents`, creating it if it doesn't exist yet. The quota and the free
+// space are checked before the old contents are truncated.
+fn replace_file(
+    fs: &FileSystem,
+    dir: &Dir,
+    name: &str,
+    components: &[String],
+    contents: &[u8],
+) -> FsResult<()> {
+    let size = find_entry(dir, name)?.map_or(0, |entry| entry.len());
+    check_growth(fs, size, contents.len() as u64)?;
+    home::reserve_file(fs, components, contents.len() as u64)?;
+    let mut file = create_file(dir, name)?;
+    write_or_trap(components, || {
         file.truncate()?;
-        file.write_all(&contents.into_bytes())?;
-        file.flush()?;
-        std::io::Result::Ok(())
+        file.write_all(contents)?;
+        Ok(file.flush()?)
+    });
+    Ok(())
+}
+
+// FAT keeps file sizes in 32 bits.
+const MAX_FILE_SIZE: u64 = u32::MAX as u64;
+
+// Checks that a file growing from `size` to `len` bytes stays within the FAT
+// file size limit and the free space, before anything is written.
+fn check_growth(fs: &FileSystem, size: u64, len: u64) -> FsResult<()> {
+    if len > MAX_FILE_SIZE {
+        return Err(FsError::InvalidArgument(format!(
+            "Files can't be larger than {} bytes",
+            MAX_FILE_SIZE
+        )));
+    }
+
+    let stats = fs.stats()?;
+    let cluster_size = u64::from(stats.cluster_size());
+    let clusters = |bytes: u64| (bytes + cluster_size - 1) / cluster_size;
+    if clusters(len).saturating_sub(clusters(size)) > u64::from(stats.free_clusters()) {
+        return Err(FsError::NoSpace);
+    }
+    Ok(())
+}
+
+// Grows `file` to `len` bytes by writing zeros at its end, which is at `size`.
+fn zero_fill(file: &mut File, size: u64, len: u64) -> FsResult<()> {
+    const ZEROS: [u8; 4096] = [0; 4096];
+
+    file.seek(SeekFrom::Start(size))?;
+    let mut remaining = len.saturating_sub(size);
+    while remaining > 0 {
+        let n = remaining.min(ZEROS.len() as u64) as usize;
+        file.write_all(&ZEROS[..n])?;
+        remaining -= n as u64;
+    }
+    Ok(())
+}
+
+/// Appends `contents` to the file at `path` and returns its new length.
+/// Fails with `NotFound` for a missing file unless `create` is set.
+#[update]
+fn append(path: String, contents: serde_bytes::ByteBuf, create: bool) -> FsResult<u64> {
+    with_fs(|fs| {
+        let components = canonical(fs, &path)?;
+        acl::check(fs, &components, Permission::Write)?;
+        home::ensure(fs, &components)?;
+        let (dir, file_name) = open_parent(fs, &path)?;
+        reserve_append(fs, &dir, &file_name, &components, contents.len() as u64)?;
+        let mut file = if create {
+            create_file(&dir, &file_name)?
+        } else {
+            open_file(&dir, &file_name)?
+        };
+        let size = file.seek(SeekFrom::End(0))?;
+        write_or_trap(&components, || {
+            file.write_all(&contents)?;
+            Ok(file.flush()?)
+        });
+        record(fs, &[Change::Written(components)]);
+        Ok(size + contents.len() as u64)
+    })
+}
+
+/// Cuts the file at `path` down to `len` bytes, or extends it with zeros up
+/// to `len`, and returns the new length.
+#[update]
+fn truncate(path: String, len: u64) -> FsResult<u64> {
+    with_fs(|fs| {
+        let components = canonical(fs, &path)?;
+        acl::check(fs, &components, Permission::Write)?;
+        home::ensure(fs, &components)?;
+        let (dir, file_name) = open_parent(fs, &path)?;
+        let mut file = open_file(&dir, &file_name)?;
+        let size = file.seek(SeekFrom::End(0))?;
+        if len >= size {
+            check_growth(fs, size, len)?;
+            home::reserve_file(fs, &components, len)?;
+        }
+        write_or_trap(&components, || {
+            if len < size {
+                file.seek(SeekFrom::Start(len))?;
+                file.truncate()?;
+            } else {
+                zero_fill(&mut file, size, len)?;
+            }
+            Ok(file.flush()?)
+        });
+        record(fs, &[Change::Written(components)]);
+        Ok(len)
+    })
+}
+
+/// Overwrites the file at `path` with `contents` starting at `offset`, in
+/// place, and returns its new length. Writing past the end extends the
+/// file, filling any gap with zeros.
+#[update]
+fn pwrite(path: String, offset: u64, contents: serde_bytes::ByteBuf) -> FsResult<u64> {
+    with_fs(|fs| {
+        let components = canonical(fs, &path)?;
+        acl::check(fs, &components, Permission::Write)?;
+        home::ensure(fs, &components)?;
+        let (dir, file_name) = open_parent(fs, &path)?;
+        let mut file = open_file(&dir, &file_name)?;
+        let size = file.seek(SeekFrom::End(0))?;
+        let end = offset
+            .checked_add(contents.len() as u64)
+            .ok_or_else(|| FsError::InvalidArgument("Offset out of range".to_string()))?;
+        if end > size {
+            check_growth(fs, size, end)?;
+            home::reserve_file(fs, &components, end)?;
+        }
+        write_or_trap(&components, || {
+            if offset > size {
+                zero_fill(&mut file, size, offset)?;
+            }
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&contents)?;
+            Ok(file.flush()?)
+        });
+        record(fs, &[Change::Written(components)]);
+        Ok(size.max(end))
     })
-    .unwrap()
-}
\ No newline at end of file
+}
@@ -0,0 +1,455 @@
+use std::{cell::RefCell, collections::BTreeMap};
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::{query, update};
+
+use super::{
+    canonical, find_entry, home, is_mounted, open_dir_components, path, system, with_fs, Change,
+    FileSystem, FsError, FsResult,
+};
+
+const ACL_FILE: &str = "acl";
+
+thread_local! {
+    // Whoever installed or last upgraded the canister, which can always
+    // manage the volume.
+    static CONTROLLER: RefCell<Option<Principal>> = RefCell::new(None);
+    // The root's owner and `Admin` grantees when the volume was last
+    // unmounted, as its ACLs can't be read until it is mounted again.
+    static VOLUME_ADMINS: RefCell<Vec<Principal>> = RefCell::default();
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Permission {
+    Read,
+    Write,
+    // Changing the ACL itself. Implies read and write.
+    Admin,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Grant {
+    pub principal: Principal,
+    pub permissions: Vec<Permission>,
+}
+
+/// An ACL set on a path. It governs the path and everything below it that
+/// has no ACL of its own.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PathAcl {
+    path: String,
+    owner: Principal,
+    grants: Vec<Grant>,
+}
+
+/// The ACL in effect for a path. `inherited_from` names the ancestor it was
+/// set on, if not on the path itself. Paths without any ACL above them have
+/// no owner and are open to everyone.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FileAcl {
+    pub path: String,
+    pub owner: Option<Principal>,
+    pub grants: Vec<Grant>,
+    pub inherited_from: Option<String>,
+}
+
+// Keyed by `key`, so lookups are case-insensitive like FAT names.
+pub struct Acls(BTreeMap<String, PathAcl>);
+
+fn key(components: &[String]) -> String {
+    path::join(components).to_uppercase()
+}
+
+impl Acls {
+    pub fn load(fs: &FileSystem) -> FsResult<Self> {
+        let acls: Vec<PathAcl> = system::load(fs, ACL_FILE)?.unwrap_or_default();
+        Ok(Acls(
+            acls.into_iter()
+                .map(|acl| (acl.path.to_uppercase(), acl))
+                .collect(),
+        ))
+    }
+
+    fn store(&self, fs: &FileSystem) -> FsResult<()> {
+        system::store(fs, ACL_FILE, &self.0.values().collect::<Vec<_>>())
+    }
+
+    // The ACL set on `components` or its closest ancestor that has one.
+    fn governing(&self, components: &[String]) -> Option<&PathAcl> {
+        (0..=components.len())
+            .rev()
+            .find_map(|len| self.0.get(&key(&components[..len])))
+    }
+
+    /// Whether `principal` holds `permission` on `components`. Owners of an
+    /// ACL anywhere above a path have every permission on it, so setting a
+    /// stricter ACL further down can't lock them out.
+    pub fn allows(
+        &self,
+        components: &[String],
+        principal: Principal,
+        permission: Permission,
+    ) -> bool {
+        let owns_ancestor = (0..=components.len()).any(|len| {
+            self.0
+                .get(&key(&components[..len]))
+                .map_or(false, |acl| acl.owner == principal)
+        });
+        if owns_ancestor {
+            return true;
+        }
+
+        self.governing(components).map_or(true, |acl| {
+            acl.grants.iter().any(|grant| {
+                grant.principal == principal
+                    && (grant.permissions.contains(&permission)
+                        || grant.permissions.contains(&Permission::Admin))
+            })
+        })
+    }
+
+    pub fn check(&self, components: &[String], permission: Permission) -> FsResult<()> {
+        self.check_as(components, ic_cdk::caller(), permission)
+    }
+
+    fn check_as(
+        &self,
+        components: &[String],
+        principal: Principal,
+        permission: Permission,
+    ) -> FsResult<()> {
+        if self.allows(components, principal, permission) {
+            Ok(())
+        } else {
+            Err(FsError::PermissionDenied)
+        }
+    }
+
+    // Keys of the ACLs set strictly below `components`.
+    fn below(&self, components: &[String]) -> Vec<String> {
+        let own = key(components);
+        let prefix = if components.is_empty() {
+            own.clone()
+        } else {
+            format!("{}/", own)
+        };
+        self.0
+            .range(prefix.clone()..)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(&prefix))
+            .filter(|key| **key != own)
+            .cloned()
+            .collect()
+    }
+
+    fn remove_prefix(&mut self, components: &[String]) -> Vec<PathAcl> {
+        let mut keys = self.below(components);
+        keys.push(key(components));
+        keys.iter().filter_map(|key| self.0.remove(key)).collect()
+    }
+}
+
+/// Fails with `PermissionDenied` unless the caller holds `permission` on
+/// `components`. Writes into the caller's home directory pass while it has
+/// no ACL of its own, as writing endpoints make the caller its owner first,
+/// see `home::ensure`. The check itself never touches the volume.
+pub fn check(fs: &FileSystem, components: &[String], permission: Permission) -> FsResult<()> {
+    let acls = Acls::load(fs)?;
+    if permission == Permission::Write && home::is_own(components) {
+        let home = home::home_components(ic_cdk::caller());
+        if !acls.0.contains_key(&key(&home)) {
+            return Ok(());
+        }
+    }
+    acls.check(components, permission)
+}
+
+pub fn check_path(fs: &FileSystem, path: &str, permission: Permission) -> FsResult<()> {
+    check(fs, &canonical(fs, path)?, permission)
+}
+
+/// Like `check`, but also for every path below `components` with an ACL of
+/// its own, for operations on a whole tree.
+pub fn check_tree(fs: &FileSystem, components: &[String], permission: Permission) -> FsResult<()> {
+    check(fs, components, permission)?;
+    let acls = Acls::load(fs)?;
+    for key in acls.below(components) {
+        acls.check(&path::normalize(&key)?, permission)?;
+    }
+    Ok(())
+}
+
+/// Records the caller as the canister's controller. Called on install and
+/// upgrade, which only controllers can do.
+pub fn remember_controller() {
+    CONTROLLER.with(|controller| *controller.borrow_mut() = Some(ic_cdk::caller()));
+}
+
+/// Records who may manage the volume while it is unmounted. Called right
+/// before unmounting.
+pub fn remember_volume_admins(fs: &FileSystem) {
+    let admins = match Acls::load(fs) {
+        Ok(acls) => acls.0.get(&key(&[])).map_or_else(Vec::new, |acl| {
+            let grantees = acl
+                .grants
+                .iter()
+                .filter(|grant| grant.permissions.contains(&Permission::Admin))
+                .map(|grant| grant.principal);
+            std::iter::once(acl.owner).chain(grantees).collect()
+        }),
+        Err(_) => vec![],
+    };
+    VOLUME_ADMINS.with(|volume_admins| *volume_admins.borrow_mut() = admins);
+}
+
+/// Operations on the volume as a whole need `Admin` on the root. While the
+/// volume isn't mounted, only those who had it when it was unmounted qualify.
+/// The controller always does, anonymous callers never do.
+pub fn check_volume() -> FsResult<()> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(FsError::PermissionDenied);
+    }
+    if CONTROLLER.with(|controller| *controller.borrow() == Some(caller)) {
+        return Ok(());
+    }
+    if is_mounted() {
+        return with_fs(|fs| check(fs, &[], Permission::Admin));
+    }
+
+    if VOLUME_ADMINS.with(|admins| admins.borrow().contains(&caller)) {
+        Ok(())
+    } else {
+        Err(FsError::PermissionDenied)
+    }
+}
+
+/// Makes the caller the owner of the root, replacing any ACLs. Used on
+/// freshly formatted volumes, which anonymous callers can't own.
+pub fn claim_root(fs: &FileSystem) -> FsResult<()> {
+    let owner = ic_cdk::caller();
+    if owner == Principal::anonymous() {
+        return Err(FsError::PermissionDenied);
+    }
+
+    let mut acls = BTreeMap::new();
+    acls.insert(
+        key(&[]),
+        PathAcl {
+            path: path::join(&[]),
+            owner,
+            grants: vec![],
+        },
+    );
+    Acls(acls).store(fs)
+}
+
+/// Makes `owner` the owner of `components` unless it has an ACL of its own
+/// already.
+pub fn init_owner(fs: &FileSystem, components: &[String], owner: Principal) -> FsResult<()> {
+    let mut acls = Acls::load(fs)?;
+    if acls.0.contains_key(&key(components)) {
+        return Ok(());
+    }
+
+    acls.0.insert(
+        key(components),
+        PathAcl {
+            path: path::join(components),
+            owner,
+            grants: vec![],
+        },
+    );
+    acls.store(fs)
+}
+
+/// Takes the ACLs set on `components` and below out, so they can go along
+/// with an entry moved to the trash instead of being dropped with it.
+pub fn detach(fs: &FileSystem, components: &[String]) -> FsResult<Vec<PathAcl>> {
+    let mut acls = Acls::load(fs)?;
+    let detached = acls.remove_prefix(components);
+    if !detached.is_empty() {
+        acls.store(fs)?;
+    }
+    Ok(detached)
+}
+
+/// Puts back ACLs taken out by `detach` once their entry is restored to the
+/// path it was trashed from.
+pub fn attach(fs: &FileSystem, detached: Vec<PathAcl>) -> FsResult<()> {
+    if detached.is_empty() {
+        return Ok(());
+    }
+
+    let mut acls = Acls::load(fs)?;
+    for acl in detached {
+        acls.0.insert(acl.path.to_uppercase(), acl);
+    }
+    acls.store(fs)
+}
+
+/// Moves ACLs along with renamed paths and drops those of removed ones.
+pub fn apply(fs: &FileSystem, changes: &[Change]) -> FsResult<()> {
+    let mut acls = Acls::load(fs)?;
+    if acls.0.is_empty() {
+        return Ok(());
+    }
+
+    let mut changed = false;
+    for change in changes {
+        match change {
+            Change::Written(_) | Change::Created(_) => {}
+            Change::Removed(components) => {
+                changed |= !acls.remove_prefix(components).is_empty();
+            }
+            Change::Renamed(from, to) => {
+                for mut acl in acls.remove_prefix(from) {
+                    let mut components = to.clone();
+                    components.extend(path::normalize(&acl.path)?.into_iter().skip(from.len()));
+                    acl.path = path::join(&components);
+                    acls.0.insert(key(&components), acl);
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    if changed {
+        acls.store(fs)?;
+    }
+    Ok(())
+}
+
+fn require_exists(fs: &FileSystem, components: &[String]) -> FsResult<()> {
+    match components.split_last() {
+        Some((name, parent)) => {
+            find_entry(&open_dir_components(fs, parent)?, name)?.ok_or(FsError::NotFound)?;
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+// The ACL set on `components` itself, starting out as a copy of the one it
+// inherits so that changing it doesn't silently drop inherited grants.
+fn own_acl<'a>(acls: &'a mut Acls, components: &[String]) -> &'a mut PathAcl {
+    let inherited = acls.governing(components).cloned();
+    acls.0.entry(key(components)).or_insert_with(|| PathAcl {
+        path: path::join(components),
+        owner: inherited
+            .as_ref()
+            .map_or_else(ic_cdk::caller, |acl| acl.owner),
+        grants: inherited.map(|acl| acl.grants).unwrap_or_default(),
+    })
+}
+
+/// Makes `owner` the owner of `path`. Needs `Admin` on it.
+#[update]
+fn chown(path: String, owner: Principal) -> FsResult<()> {
+    with_fs(|fs| {
+        let components = canonical(fs, &path)?;
+        require_exists(fs, &components)?;
+        let mut acls = Acls::load(fs)?;
+        acls.check(&components, Permission::Admin)?;
+
+        own_acl(&mut acls, &components).owner = owner;
+        acls.store(fs)
+    })
+}
+
+/// Grants `principal` exactly `permissions` on `path` and everything below
+/// it without an ACL of its own. An empty list revokes its grant. Needs
+/// `Admin` on `path`.
+#[update]
+fn chmod(path: String, principal: Principal, permissions: Vec<Permission>) -> FsResult<()> {
+    with_fs(|fs| {
+        let components = canonical(fs, &path)?;
+        require_exists(fs, &components)?;
+        let mut acls = Acls::load(fs)?;
+        acls.check(&components, Permission::Admin)?;
+
+        let acl = own_acl(&mut acls, &components);
+        acl.grants.retain(|grant| grant.principal != principal);
+        if !permissions.is_empty() {
+            acl.grants.push(Grant {
+                principal,
+                permissions,
+            });
+        }
+        acls.store(fs)
+    })
+}
+
+#[query]
+fn getfacl(path: String) -> FsResult<FileAcl> {
+    with_fs(|fs| {
+        let components = canonical(fs, &path)?;
+        require_exists(fs, &components)?;
+        let acls = Acls::load(fs)?;
+        acls.check(&components, Permission::Read)?;
+
+        let path = path::join(&components);
+        Ok(match acls.governing(&components) {
+            Some(acl) => FileAcl {
+                path: path.clone(),
+                owner: Some(acl.owner),
+                grants: acl.grants.clone(),
+                inherited_from: Some(acl.path.clone()).filter(|from| *from != path),
+            },
+            None => FileAcl {
+                path,
+                owner: None,
+                grants: vec![],
+                inherited_from: None,
+            },
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Resolves names as on a volume holding `/shared/Private notes`, whose
+    // 8.3 alias is `PRIVAT~1`.
+    fn long_name(dir: &[String], name: &str) -> FsResult<Option<String>> {
+        let entries: &[(&str, &str)] = match dir.len() {
+            0 => &[("shared", "SHARED")],
+            1 => &[("Private notes", "PRIVAT~1")],
+            _ => &[],
+        };
+        Ok(entries
+            .iter()
+            .find(|(long, short)| {
+                long.eq_ignore_ascii_case(name) || short.eq_ignore_ascii_case(name)
+            })
+            .map(|(long, _)| long.to_string()))
+    }
+
+    #[test]
+    fn short_names_are_held_to_the_acl_of_the_long_name() {
+        let owner = Principal::from_slice(&[1]);
+        let other = Principal::from_slice(&[2]);
+        let private = vec!["shared".to_string(), "Private notes".to_string()];
+        let mut acls = Acls(BTreeMap::new());
+        acls.0.insert(
+            key(&private),
+            PathAcl {
+                path: path::join(&private),
+                owner,
+                grants: vec![],
+            },
+        );
+
+        for alias in &["/shared/PRIVAT~1/todo.txt", "/SHARED/privat~1/todo.txt"] {
+            let components =
+                path::canonicalize(path::normalize(alias).unwrap(), long_name).unwrap();
+            assert_eq!(path::join(&components), "/shared/Private notes/todo.txt");
+            assert_eq!(
+                acls.check_as(&components, other, Permission::Read),
+                Err(FsError::PermissionDenied)
+            );
+            assert_eq!(acls.check_as(&components, owner, Permission::Read), Ok(()));
+        }
+    }
+}
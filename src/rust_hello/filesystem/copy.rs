@@ -0,0 +1,228 @@
+use std::io::Read;
+
+use candid::{CandidType, Deserialize};
+use fatfs::Write;
+use ic_cdk_macros::update;
+
+use super::{
+    acl::{self, Permission},
+    canonical, find_entry, home, open_dir_components, open_parent, path, record, starts_with_path,
+    trash,
+    tree::{self, Visit},
+    with_fs, Change, Dir, DirEntry, File, FileSystem, FsError, FsResult,
+};
+
+// Files are copied through a fixed buffer so their size doesn't matter for the heap.
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+// Entries and bytes a single call copies before it stops and reports where
+// to continue, so large trees don't run into the instruction limit. A file
+// is always copied whole.
+const ENTRY_BUDGET: u64 = 1_000;
+const BYTE_BUDGET: u64 = 32 * 1024 * 1024;
+
+/// What a `cp` call copied. Until `complete` is true, call `cp` again with
+/// `target` as `dst` and `next` as `cursor`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CopyReport {
+    pub copied: u64,
+    // The path of the copy itself, which differs from `dst` when the copy
+    // was placed inside an existing directory.
+    pub target: String,
+    pub complete: bool,
+    pub next: Option<String>,
+}
+
+// Copies the contents of `source` and carries over its timestamps. fatfs has
+// no way to set attributes, so those start out fresh on the copy.
+fn copy_file(source: &DirEntry, target: &mut File) -> FsResult<()> {
+    let mut reader = source.to_file();
+    let mut buf = vec![0; COPY_BUFFER_SIZE];
+
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        target.write_all(&buf[..read])?;
+    }
+
+    target.set_created(source.created());
+    target.set_modified(source.modified());
+    target.flush()?;
+    Ok(())
+}
+
+// Copies the file `source` to `name` in `dir`, at `components`. A file that
+// is already there goes to the trash rather than being overwritten.
+fn copy_to(
+    fs: &FileSystem,
+    source: &DirEntry,
+    dir: &Dir,
+    name: &str,
+    components: &[String],
+    changes: &mut Vec<Change>,
+) -> FsResult<()> {
+    match find_entry(dir, name)? {
+        Some(existing) if existing.is_dir() => return Err(FsError::IsADirectory),
+        Some(_) => {
+            trash::discard(fs, dir, name, components)?;
+            changes.push(Change::Removed(components.to_vec()));
+        }
+        None => {}
+    }
+
+    copy_file(source, &mut dir.create_file(name)?)?;
+    changes.push(Change::Written(components.to_vec()));
+    Ok(())
+}
+
+// Makes sure there is a directory `name` in `dir`, at `components`, to copy
+// into; an existing one is merged into.
+fn copy_dir(
+    dir: &Dir,
+    name: &str,
+    components: &[String],
+    changes: &mut Vec<Change>,
+) -> FsResult<()> {
+    match find_entry(dir, name)? {
+        Some(existing) if existing.is_dir() => Ok(()),
+        Some(_) => Err(FsError::NotADirectory),
+        None => {
+            dir.create_dir(name)?;
+            changes.push(Change::Created(components.to_vec()));
+            Ok(())
+        }
+    }
+}
+
+// Copies what is below the directory `source`, at `src`, to the same place
+// below `dst`, in walk order after `after`, until the budget runs out.
+fn copy_tree(
+    fs: &FileSystem,
+    source: &Dir,
+    src: &[String],
+    dst: &[String],
+    after: Option<&[String]>,
+    report: &mut CopyReport,
+    changes: &mut Vec<Change>,
+) -> FsResult<()> {
+    let mut bytes = 0;
+    let mut visit = |entry: &DirEntry, components: &[String], _depth: u32| -> FsResult<Visit> {
+        let mut target = dst.to_vec();
+        target.extend_from_slice(&components[src.len()..]);
+        let (name, parent) = target.split_last().ok_or(FsError::InvalidPath)?;
+        let dir = open_dir_components(fs, parent)?;
+        if entry.is_dir() {
+            copy_dir(&dir, name, &target, changes)?;
+        } else {
+            copy_to(fs, entry, &dir, name, &target, changes)?;
+            bytes += entry.len();
+        }
+
+        report.copied += 1;
+        if report.copied >= ENTRY_BUDGET || bytes >= BYTE_BUDGET {
+            Ok(Visit::Stop)
+        } else {
+            Ok(Visit::Continue)
+        }
+    };
+
+    let last = tree::walk_after(source, src, 1, after, &mut visit)?;
+    report.complete = last.is_none();
+    report.next = last.map(|last| path::join(&last));
+    Ok(())
+}
+
+/// Copies `src` to `dst` inside the canister. When `dst` is an existing
+/// directory the copy is placed inside it. Directories need `recursive` and
+/// are merged into an existing target directory; files already in the way
+/// go to the trash. Large trees are copied a bounded number of entries at a
+/// time, see `CopyReport`. A call that fails partway copies nothing.
+#[update]
+fn cp(src: String, dst: String, recursive: bool, cursor: Option<String>) -> FsResult<CopyReport> {
+    with_fs(|fs| {
+        let src_components = canonical(fs, &src)?;
+        acl::check_tree(fs, &src_components, Permission::Read)?;
+        let (src_dir, src_name) = open_parent(fs, &src)?;
+        let source = find_entry(&src_dir, &src_name)?.ok_or(FsError::NotFound)?;
+        if source.is_dir() && !recursive {
+            return Err(FsError::IsADirectory);
+        }
+        let after = match cursor {
+            Some(_) if !source.is_dir() => {
+                return Err(FsError::InvalidArgument(
+                    "Only directory copies take a cursor".to_string(),
+                ))
+            }
+            Some(cursor) => Some(tree::decode_cursor(&cursor, &src_components)?),
+            None => None,
+        };
+
+        // A continued copy names its target directly; it exists by now.
+        let mut dst_components = canonical(fs, &dst)?;
+        if after.is_none() && open_dir_components(fs, &dst_components).is_ok() {
+            dst_components.push(source.file_name());
+        }
+        if starts_with_path(&dst_components, &src_components) {
+            return Err(FsError::InvalidPath);
+        }
+        acl::check_tree(fs, &dst_components, Permission::Write)?;
+        home::ensure(fs, &dst_components)?;
+
+        let (dst_name, dst_parent) = dst_components.split_last().ok_or(FsError::InvalidPath)?;
+        let target_parent = open_dir_components(fs, dst_parent)?;
+        match find_entry(&target_parent, dst_name)? {
+            Some(existing) if source.is_dir() && !existing.is_dir() => {
+                return Err(FsError::NotADirectory)
+            }
+            Some(existing) if !source.is_dir() && existing.is_dir() => {
+                return Err(FsError::IsADirectory)
+            }
+            _ => {}
+        }
+        home::reserve_tree(fs, None, &dst_components, &source)?;
+
+        let mut report = CopyReport {
+            copied: 0,
+            target: path::join(&dst_components),
+            complete: true,
+            next: None,
+        };
+        let mut changes = vec![];
+        let result = if source.is_dir() {
+            copy_dir(&target_parent, dst_name, &dst_components, &mut changes).and_then(|()| {
+                copy_tree(
+                    fs,
+                    &source.to_dir(),
+                    &src_components,
+                    &dst_components,
+                    after.as_deref(),
+                    &mut report,
+                    &mut changes,
+                )
+            })
+        } else {
+            report.copied = 1;
+            copy_to(
+                fs,
+                &source,
+                &target_parent,
+                dst_name,
+                &dst_components,
+                &mut changes,
+            )
+        };
+
+        // Returning the error would commit whatever was copied so far, so a
+        // failure rolls the whole call back instead.
+        if let Err(error) = result {
+            ic_cdk::trap(&format!(
+                "Failed to copy {} to {}: {:?}",
+                src, report.target, error
+            ));
+        }
+        record(fs, &changes);
+        Ok(report)
+    })
+}
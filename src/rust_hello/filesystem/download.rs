@@ -0,0 +1,250 @@
+use std::{cell::RefCell, collections::BTreeMap, io::Read};
+
+use candid::{CandidType, Deserialize, Principal};
+use fatfs::{Seek, SeekFrom};
+use ic_cdk_macros::{query, update};
+use serde_bytes::ByteBuf;
+
+use super::{find_entry, open_file, system, with_fs, File, FileSystem, FsError, FsResult};
+
+// Tar archives are built in memory, so all archives get the same ceiling as
+// staged uploads.
+pub const MAX_DOWNLOAD_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Upper bound for the bytes that open downloads and the exports still being
+/// built hold together, on the heap or spooled, so callers can't exhaust
+/// either by starting exports and never closing them.
+pub const MAX_HELD_BYTES: u64 = 1024 * 1024 * 1024;
+
+// Downloads, finished or still being spooled, a caller may have open at once.
+const MAX_DOWNLOADS: usize = 8;
+
+// Downloads are dropped this long after they were prepared. Fetching a chunk
+// is a query, so it can't keep a download alive.
+const DOWNLOAD_TIMEOUT: u64 = 60 * 60 * 1_000_000_000;
+
+// Downloads that are too large to keep on the heap while they are built,
+// such as zip exports, are spooled to a file below this system directory,
+// named after the download id.
+const SPOOL_DIR: &str = "downloads";
+
+// Small enough to stay well below the message size limit of a query reply.
+const CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// A prepared download, fetched chunk by chunk with `download_chunk`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DownloadInfo {
+    pub id: u64,
+    pub size: u64,
+    pub chunk_size: u64,
+    pub chunk_count: u64,
+}
+
+enum Contents {
+    Memory(Vec<u8>),
+    Spooled,
+}
+
+struct DownloadSession {
+    owner: Principal,
+    // When the download was prepared, in nanoseconds since the epoch.
+    created: u64,
+    size: u64,
+    contents: Contents,
+}
+
+thread_local! {
+    static DOWNLOADS: RefCell<BTreeMap<u64, DownloadSession>> = RefCell::default();
+    static NEXT_DOWNLOAD_ID: RefCell<u64> = RefCell::new(0);
+    // Spool files that are still being written, and who for.
+    static SPOOLS: RefCell<BTreeMap<u64, Principal>> = RefCell::default();
+}
+
+fn next_id() -> u64 {
+    NEXT_DOWNLOAD_ID.with(|next| {
+        let mut next = next.borrow_mut();
+        *next += 1;
+        *next
+    })
+}
+
+// Drops expired downloads and checks that the caller may open another one.
+// Spooled files of dropped downloads go with the next `create_spool`.
+fn admit() -> FsResult<()> {
+    let owner = ic_cdk::caller();
+    let now = ic_cdk::api::time();
+    DOWNLOADS.with(|downloads| {
+        let mut downloads = downloads.borrow_mut();
+        downloads.retain(|_, session| session.created.saturating_add(DOWNLOAD_TIMEOUT) > now);
+
+        let open = downloads
+            .values()
+            .filter(|session| session.owner == owner)
+            .count()
+            + SPOOLS.with(|spools| {
+                spools
+                    .borrow()
+                    .values()
+                    .filter(|spooler| **spooler == owner)
+                    .count()
+            });
+        if open >= MAX_DOWNLOADS {
+            return Err(FsError::InvalidArgument(format!(
+                "At most {} downloads can be open at once",
+                MAX_DOWNLOADS
+            )));
+        }
+        Ok(())
+    })
+}
+
+/// Bytes that open downloads hold.
+pub fn held_bytes() -> u64 {
+    DOWNLOADS.with(|downloads| {
+        downloads
+            .borrow()
+            .values()
+            .map(|session| session.size)
+            .sum()
+    })
+}
+
+// Hands out download `id` to the caller until they close it or it expires.
+fn insert(id: u64, size: u64, contents: Contents) -> DownloadInfo {
+    DOWNLOADS.with(|downloads| {
+        downloads.borrow_mut().insert(
+            id,
+            DownloadSession {
+                owner: ic_cdk::caller(),
+                created: ic_cdk::api::time(),
+                size,
+                contents,
+            },
+        )
+    });
+
+    DownloadInfo {
+        id,
+        size,
+        chunk_size: CHUNK_SIZE,
+        chunk_count: (size + CHUNK_SIZE - 1) / CHUNK_SIZE,
+    }
+}
+
+/// Keeps `data` around for the caller until they close the download. Fails
+/// if the caller has too many downloads open, or if `data` doesn't fit
+/// below `MAX_HELD_BYTES` next to `exporting`, the bytes that exports still
+/// being built hold.
+pub fn begin(data: Vec<u8>, exporting: u64) -> FsResult<DownloadInfo> {
+    admit()?;
+    let size = data.len() as u64;
+    if held_bytes() + exporting + size > MAX_HELD_BYTES {
+        return Err(FsError::NoSpace);
+    }
+    Ok(insert(next_id(), size, Contents::Memory(data)))
+}
+
+/// Creates an empty spool file and returns the id of the download it will
+/// become; it counts as one of the caller's open downloads. Spool files that
+/// belong to neither a download nor a spool still being written, e.g. from
+/// before an upgrade or of expired downloads, are deleted first.
+pub fn create_spool(fs: &FileSystem) -> FsResult<u64> {
+    admit()?;
+    let dir = system::dir(fs, &[SPOOL_DIR])?;
+    let mut orphans = vec![];
+    for entry in dir.iter() {
+        let name = entry?.file_name();
+        let live = name.parse().map_or(false, |id| {
+            SPOOLS.with(|spools| spools.borrow().contains_key(&id))
+                || DOWNLOADS.with(|downloads| downloads.borrow().contains_key(&id))
+        });
+        if name != "." && name != ".." && !live {
+            orphans.push(name);
+        }
+    }
+    for name in orphans {
+        dir.remove(&name)?;
+    }
+
+    let id = next_id();
+    dir.create_file(&id.to_string())?;
+    SPOOLS.with(|spools| spools.borrow_mut().insert(id, ic_cdk::caller()));
+    Ok(id)
+}
+
+/// Opens spool file `id` for writing.
+pub fn spool_file<'a>(fs: &'a FileSystem, id: u64) -> FsResult<File<'a>> {
+    open_file(&system::dir(fs, &[SPOOL_DIR])?, &id.to_string())
+}
+
+/// Hands out the `size` bytes written to spool file `id` as a download.
+pub fn finish_spool(id: u64, size: u64) -> DownloadInfo {
+    SPOOLS.with(|spools| spools.borrow_mut().remove(&id));
+    insert(id, size, Contents::Spooled)
+}
+
+/// Gives up on spool file `id` without touching the volume; the file goes
+/// with the next `create_spool`.
+pub fn drop_spool(id: u64) {
+    SPOOLS.with(|spools| spools.borrow_mut().remove(&id));
+}
+
+/// Deletes spool file `id`, whether it was finished or not.
+pub fn remove_spool(fs: &FileSystem, id: u64) -> FsResult<()> {
+    drop_spool(id);
+    let dir = system::dir(fs, &[SPOOL_DIR])?;
+    if find_entry(&dir, &id.to_string())?.is_some() {
+        dir.remove(&id.to_string())?;
+    }
+    Ok(())
+}
+
+/// Returns chunk `index` of download `id`.
+#[query]
+fn download_chunk(id: u64, index: u64) -> FsResult<ByteBuf> {
+    DOWNLOADS.with(|downloads| {
+        let downloads = downloads.borrow();
+        let session = downloads
+            .get(&id)
+            .filter(|session| session.owner == ic_cdk::caller())
+            .ok_or(FsError::NotFound)?;
+
+        let size = session.size;
+        let start = index.saturating_mul(CHUNK_SIZE);
+        if start >= size {
+            return Err(FsError::InvalidArgument(format!(
+                "Chunk {} out of range for a {} byte download",
+                index, size
+            )));
+        }
+        let end = (start + CHUNK_SIZE).min(size);
+        match &session.contents {
+            Contents::Memory(data) => Ok(ByteBuf::from(&data[start as usize..end as usize])),
+            Contents::Spooled => with_fs(|fs| {
+                let mut file = spool_file(fs, id)?;
+                file.seek(SeekFrom::Start(start))?;
+                let mut chunk = vec![];
+                (&mut file).take(end - start).read_to_end(&mut chunk)?;
+                Ok(ByteBuf::from(chunk))
+            }),
+        }
+    })
+}
+
+/// Frees a download once all chunks have been fetched.
+#[update]
+fn close_download(id: u64) -> FsResult<()> {
+    let session = DOWNLOADS.with(|downloads| {
+        let mut downloads = downloads.borrow_mut();
+        match downloads.get(&id) {
+            Some(session) if session.owner == ic_cdk::caller() => Ok(downloads.remove(&id)),
+            _ => Err(FsError::NotFound),
+        }
+    })?;
+    match session.map(|session| session.contents) {
+        // A spool file left behind when the volume is unmounted goes with
+        // the next `create_spool`.
+        Some(Contents::Spooled) => with_fs(|fs| remove_spool(fs, id)),
+        _ => Ok(()),
+    }
+}
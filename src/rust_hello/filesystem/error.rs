@@ -0,0 +1,46 @@
+use candid::{CandidType, Deserialize};
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum FsError {
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    InvalidPath,
+    NoSpace,
+    NotInitialized,
+    PermissionDenied,
+    QuotaExceeded,
+    Conflict(String),
+    InvalidArgument(String),
+    Io(String),
+}
+
+pub type FsResult<T> = Result<T, FsError>;
+
+impl From<std::io::Error> for FsError {
+    fn from(error: std::io::Error) -> Self {
+        match error.kind() {
+            std::io::ErrorKind::NotFound => FsError::NotFound,
+            std::io::ErrorKind::AlreadyExists => FsError::AlreadyExists,
+            _ => FsError::Io(error.to_string()),
+        }
+    }
+}
+
+impl From<fatfs::Error<std::io::Error>> for FsError {
+    fn from(error: fatfs::Error<std::io::Error>) -> Self {
+        match error {
+            fatfs::Error::Io(error) => error.into(),
+            fatfs::Error::NotFound => FsError::NotFound,
+            fatfs::Error::AlreadyExists => FsError::AlreadyExists,
+            fatfs::Error::DirectoryIsNotEmpty => FsError::DirectoryNotEmpty,
+            fatfs::Error::NotEnoughSpace => FsError::NoSpace,
+            fatfs::Error::InvalidFileNameLength | fatfs::Error::UnsupportedFileNameCharacter => {
+                FsError::InvalidPath
+            }
+            error => FsError::Io(error.to_string()),
+        }
+    }
+}
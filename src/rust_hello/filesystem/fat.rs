@@ -0,0 +1,436 @@
+//! Raw access to the boot sector and allocation tables of the volume in
+//! stable memory, for the few operations fatfs doesn't offer. The filesystem
+//! must be unmounted while these are used.
+
+use std::convert::TryInto;
+
+use ic_cdk::api::stable::{stable64_read, stable64_size, stable64_write};
+
+use super::{FsError, FsResult};
+
+const BOOT_SECTOR_SIZE: usize = 512;
+
+// Cluster counts at which the FAT type changes. fatfs, like every other
+// implementation, derives the type from the cluster count alone.
+const MIN_FAT16_CLUSTERS: u32 = 4085;
+const MIN_FAT32_CLUSTERS: u32 = 65525;
+const MAX_FAT32_CLUSTERS: u32 = 0x0FFF_FFF5;
+
+// The first two FAT entries are reserved, data clusters are numbered from 2.
+pub const RESERVED_FAT_ENTRIES: u32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    pub fn from_clusters(clusters: u32) -> Self {
+        if clusters < MIN_FAT16_CLUSTERS {
+            FatType::Fat12
+        } else if clusters < MIN_FAT32_CLUSTERS {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        }
+    }
+
+    fn max_clusters(self) -> u32 {
+        match self {
+            FatType::Fat12 => MIN_FAT16_CLUSTERS - 1,
+            FatType::Fat16 => MIN_FAT32_CLUSTERS - 1,
+            FatType::Fat32 => MAX_FAT32_CLUSTERS - 1,
+        }
+    }
+
+    fn min_clusters(self) -> u32 {
+        match self {
+            FatType::Fat12 => 1,
+            FatType::Fat16 => MIN_FAT16_CLUSTERS,
+            FatType::Fat32 => MIN_FAT32_CLUSTERS,
+        }
+    }
+
+    fn bits_per_entry(self) -> u64 {
+        match self {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        }
+    }
+
+    pub fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0x0FFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    /// Whether `value` ends a cluster chain.
+    pub fn is_end_of_chain(self, value: u32) -> bool {
+        value >= self.end_of_chain() - 7
+    }
+
+    /// Whether `value` marks a bad cluster.
+    pub fn is_bad(self, value: u32) -> bool {
+        value == self.end_of_chain() - 8
+    }
+}
+
+pub fn read_u16(bytes: &[u8], offset: usize) -> u32 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]]).into()
+}
+
+pub fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// The BIOS parameter block fields the raw helpers rely on.
+#[derive(Clone, Debug)]
+pub struct BootSector {
+    pub bytes_per_sector: u32,
+    pub sectors_per_cluster: u32,
+    pub reserved_sectors: u32,
+    pub fats: u32,
+    pub root_entries: u32,
+    pub total_sectors: u32,
+    pub sectors_per_fat: u32,
+    pub root_dir_cluster: u32,
+    pub fs_info_sector: u32,
+    pub backup_boot_sector: u32,
+}
+
+impl BootSector {
+    pub fn parse(bytes: &[u8]) -> FsResult<Self> {
+        let invalid = || FsError::Io("Invalid FAT boot sector".to_string());
+        if bytes.len() < BOOT_SECTOR_SIZE || bytes[510] != 0x55 || bytes[511] != 0xAA {
+            return Err(invalid());
+        }
+
+        let total_sectors = match read_u16(bytes, 0x13) {
+            0 => read_u32(bytes, 0x20),
+            total_sectors => total_sectors,
+        };
+        let sectors_per_fat_16 = read_u16(bytes, 0x16);
+        let is_fat32 = sectors_per_fat_16 == 0;
+
+        let boot_sector = BootSector {
+            bytes_per_sector: read_u16(bytes, 0x0B),
+            sectors_per_cluster: bytes[0x0D].into(),
+            reserved_sectors: read_u16(bytes, 0x0E),
+            fats: bytes[0x10].into(),
+            root_entries: read_u16(bytes, 0x11),
+            total_sectors,
+            sectors_per_fat: if is_fat32 {
+                read_u32(bytes, 0x24)
+            } else {
+                sectors_per_fat_16
+            },
+            root_dir_cluster: if is_fat32 { read_u32(bytes, 0x2C) } else { 0 },
+            fs_info_sector: if is_fat32 { read_u16(bytes, 0x30) } else { 0 },
+            backup_boot_sector: if is_fat32 { read_u16(bytes, 0x32) } else { 0 },
+        };
+
+        let valid = boot_sector.bytes_per_sector.is_power_of_two()
+            && (512..=4096).contains(&boot_sector.bytes_per_sector)
+            && boot_sector.sectors_per_cluster.is_power_of_two()
+            && boot_sector.reserved_sectors > 0
+            && boot_sector.fats > 0
+            && boot_sector.sectors_per_fat > 0
+            && boot_sector.total_sectors > boot_sector.first_data_sector();
+        if valid {
+            Ok(boot_sector)
+        } else {
+            Err(invalid())
+        }
+    }
+
+    /// Reads the boot sector of the volume in stable memory.
+    pub fn read() -> FsResult<Self> {
+        if stable64_size() == 0 {
+            return Err(FsError::NotInitialized);
+        }
+
+        let mut bytes = [0; BOOT_SECTOR_SIZE];
+        stable64_read(0, &mut bytes);
+        Self::parse(&bytes)
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        u64::from(self.bytes_per_sector) * u64::from(self.sectors_per_cluster)
+    }
+
+    fn root_dir_sectors(&self) -> u32 {
+        (self.root_entries * 32 + self.bytes_per_sector - 1) / self.bytes_per_sector
+    }
+
+    pub fn first_data_sector(&self) -> u32 {
+        self.reserved_sectors + self.fats * self.sectors_per_fat + self.root_dir_sectors()
+    }
+
+    pub fn total_clusters(&self) -> u32 {
+        (self.total_sectors - self.first_data_sector()) / self.sectors_per_cluster
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        FatType::from_clusters(self.total_clusters())
+    }
+
+    pub fn fat_size(&self) -> u64 {
+        u64::from(self.sectors_per_fat) * u64::from(self.bytes_per_sector)
+    }
+
+    pub fn fat_offset(&self, fat: u32) -> u64 {
+        (u64::from(self.reserved_sectors) + u64::from(fat) * u64::from(self.sectors_per_fat))
+            * u64::from(self.bytes_per_sector)
+    }
+
+    /// Byte offset of the fixed root directory of FAT12/16 volumes.
+    pub fn root_dir_offset(&self) -> u64 {
+        self.fat_offset(self.fats)
+    }
+
+    pub fn root_dir_size(&self) -> u64 {
+        u64::from(self.root_dir_sectors()) * u64::from(self.bytes_per_sector)
+    }
+
+    pub fn cluster_offset(&self, cluster: u32) -> u64 {
+        (u64::from(self.first_data_sector())
+            + u64::from(cluster - RESERVED_FAT_ENTRIES) * u64::from(self.sectors_per_cluster))
+            * u64::from(self.bytes_per_sector)
+    }
+
+    pub fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= RESERVED_FAT_ENTRIES && cluster < self.total_clusters() + RESERVED_FAT_ENTRIES
+    }
+
+    /// The largest cluster count the allocation tables have room for without
+    /// changing the FAT type.
+    pub fn max_clusters(&self) -> u32 {
+        let fat_type = self.fat_type();
+        let entries = self.fat_size() * 8 / fat_type.bits_per_entry();
+        let addressable = entries.saturating_sub(RESERVED_FAT_ENTRIES.into());
+        addressable.min(fat_type.max_clusters().into()) as u32
+    }
+
+    /// The sector count that gives the volume `clusters` data clusters.
+    pub fn sectors_for_clusters(&self, clusters: u32) -> u32 {
+        self.first_data_sector() + clusters * self.sectors_per_cluster
+    }
+}
+
+/// An allocation table loaded into the heap.
+#[derive(PartialEq)]
+pub struct Fat {
+    fat_type: FatType,
+    bytes: Vec<u8>,
+}
+
+impl Fat {
+    pub fn read(boot_sector: &BootSector, fat: u32) -> Self {
+        let mut bytes = vec![0; boot_sector.fat_size() as usize];
+        stable64_read(boot_sector.fat_offset(fat), &mut bytes);
+        Fat {
+            fat_type: boot_sector.fat_type(),
+            bytes,
+        }
+    }
+
+    pub fn write(&self, boot_sector: &BootSector, fat: u32) {
+        stable64_write(boot_sector.fat_offset(fat), &self.bytes);
+    }
+
+    pub fn get(&self, cluster: u32) -> u32 {
+        let cluster = cluster as usize;
+        match self.fat_type {
+            FatType::Fat12 => {
+                let value = read_u16(&self.bytes, cluster + cluster / 2);
+                if cluster % 2 == 1 {
+                    value >> 4
+                } else {
+                    value & 0x0FFF
+                }
+            }
+            FatType::Fat16 => read_u16(&self.bytes, cluster * 2),
+            FatType::Fat32 => read_u32(&self.bytes, cluster * 4) & 0x0FFF_FFFF,
+        }
+    }
+
+    pub fn set(&mut self, cluster: u32, value: u32) {
+        let cluster = cluster as usize;
+        match self.fat_type {
+            FatType::Fat12 => {
+                let offset = cluster + cluster / 2;
+                let old = read_u16(&self.bytes, offset);
+                let new = if cluster % 2 == 1 {
+                    (old & 0x000F) | (value << 4)
+                } else {
+                    (old & 0xF000) | (value & 0x0FFF)
+                };
+                self.bytes[offset..offset + 2].copy_from_slice(&(new as u16).to_le_bytes());
+            }
+            FatType::Fat16 => {
+                self.bytes[cluster * 2..cluster * 2 + 2]
+                    .copy_from_slice(&(value as u16).to_le_bytes());
+            }
+            FatType::Fat32 => {
+                // The top four bits are reserved and must be preserved.
+                let old = read_u32(&self.bytes, cluster * 4);
+                let new = (old & 0xF000_0000) | (value & 0x0FFF_FFFF);
+                self.bytes[cluster * 4..cluster * 4 + 4].copy_from_slice(&new.to_le_bytes());
+            }
+        }
+    }
+}
+
+// Marks the FSInfo free cluster count and next free hint as unknown, so the
+// next mount recounts instead of trusting stale numbers.
+pub fn invalidate_fs_info(boot_sector: &BootSector) {
+    if boot_sector.fs_info_sector == 0 {
+        return;
+    }
+
+    let offset = u64::from(boot_sector.fs_info_sector) * u64::from(boot_sector.bytes_per_sector);
+    stable64_write(offset + 488, &[0xFF; 8]);
+}
+
+fn write_total_sectors(offset: u64, total_sectors: u32) {
+    let mut bytes = [0; BOOT_SECTOR_SIZE];
+    stable64_read(offset, &mut bytes);
+
+    if read_u16(&bytes, 0x13) != 0 && total_sectors <= u16::MAX.into() {
+        bytes[0x13..0x15].copy_from_slice(&(total_sectors as u16).to_le_bytes());
+    } else {
+        bytes[0x13..0x15].copy_from_slice(&[0, 0]);
+        bytes[0x20..0x24].copy_from_slice(&total_sectors.to_le_bytes());
+    }
+    stable64_write(offset, &bytes);
+}
+
+/// Checks that `resize` can give the volume `clusters` data clusters, which
+/// needs nothing but the boot sector.
+pub fn check_resize(boot_sector: &BootSector, clusters: u32) -> FsResult<()> {
+    if clusters > boot_sector.max_clusters() || clusters < boot_sector.fat_type().min_clusters() {
+        return Err(FsError::NoSpace);
+    }
+    Ok(())
+}
+
+/// Changes the number of data clusters of the volume without moving anything.
+///
+/// Clusters beyond the new end must be free; they are marked as used in every
+/// FAT copy the same way fatfs fills the unused tail of a freshly formatted
+/// table, and clusters gained are marked free. The cluster count has to stay
+/// within what the tables can address and within the current FAT type.
+pub fn resize(boot_sector: &BootSector, clusters: u32) -> FsResult<BootSector> {
+    check_resize(boot_sector, clusters)?;
+    let fat_type = boot_sector.fat_type();
+
+    let old_clusters = boot_sector.total_clusters();
+    let (start, end) = if clusters > old_clusters {
+        (old_clusters, clusters)
+    } else {
+        (clusters, old_clusters)
+    };
+    let (start, end) = (start + RESERVED_FAT_ENTRIES, end + RESERVED_FAT_ENTRIES);
+
+    let mut fats = (0..boot_sector.fats)
+        .map(|fat| Fat::read(boot_sector, fat))
+        .collect::<Vec<_>>();
+    for fat in fats.iter_mut() {
+        for cluster in start..end {
+            if clusters > old_clusters {
+                fat.set(cluster, 0);
+            } else if fat.get(cluster) != 0 {
+                return Err(FsError::NoSpace);
+            } else {
+                fat.set(cluster, fat_type.end_of_chain());
+            }
+        }
+    }
+    for (index, fat) in fats.iter().enumerate() {
+        fat.write(boot_sector, index as u32);
+    }
+
+    let total_sectors = boot_sector.sectors_for_clusters(clusters);
+    write_total_sectors(0, total_sectors);
+    if boot_sector.backup_boot_sector != 0 {
+        let offset =
+            u64::from(boot_sector.backup_boot_sector) * u64::from(boot_sector.bytes_per_sector);
+        write_total_sectors(offset, total_sectors);
+    }
+    invalidate_fs_info(boot_sector);
+
+    BootSector::read()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fat(fat_type: FatType, entries: usize) -> Fat {
+        Fat {
+            fat_type,
+            bytes: vec![0; entries * fat_type.bits_per_entry() as usize / 8 + 1],
+        }
+    }
+
+    #[test]
+    fn fat12_entries_share_bytes_without_clobbering() {
+        let mut fat = fat(FatType::Fat12, 8);
+        fat.set(2, 0xABC);
+        fat.set(3, 0x123);
+        fat.set(4, 0xFFF);
+        assert_eq!(fat.get(2), 0xABC);
+        assert_eq!(fat.get(3), 0x123);
+        assert_eq!(fat.get(4), 0xFFF);
+        assert_eq!(&fat.bytes[3..6], &[0xBC, 0x3A, 0x12]);
+
+        fat.set(3, 0);
+        assert_eq!(fat.get(2), 0xABC);
+        assert_eq!(fat.get(3), 0);
+        assert_eq!(fat.get(4), 0xFFF);
+    }
+
+    #[test]
+    fn fat12_values_are_cut_to_twelve_bits() {
+        let mut fat = fat(FatType::Fat12, 4);
+        fat.set(1, 0x1FFF);
+        assert_eq!(fat.get(0), 0);
+        assert_eq!(fat.get(1), 0xFFF);
+        assert_eq!(fat.get(2), 0);
+    }
+
+    #[test]
+    fn fat16_and_fat32_entries() {
+        let mut fat16 = fat(FatType::Fat16, 4);
+        fat16.set(2, 0xFFF8);
+        assert_eq!(fat16.get(2), 0xFFF8);
+        assert_eq!(fat16.get(3), 0);
+
+        let mut fat32 = fat(FatType::Fat32, 4);
+        fat32.bytes[8..12].copy_from_slice(&0xA000_0000u32.to_le_bytes());
+        fat32.set(2, 0xFFFF_FFFF);
+        assert_eq!(fat32.get(2), 0x0FFF_FFFF);
+        assert_eq!(read_u32(&fat32.bytes, 8), 0xAFFF_FFFF);
+    }
+
+    #[test]
+    fn fat_type_follows_the_cluster_count() {
+        assert_eq!(
+            FatType::from_clusters(MIN_FAT16_CLUSTERS - 1),
+            FatType::Fat12
+        );
+        assert_eq!(FatType::from_clusters(MIN_FAT16_CLUSTERS), FatType::Fat16);
+        assert_eq!(
+            FatType::from_clusters(MIN_FAT32_CLUSTERS - 1),
+            FatType::Fat16
+        );
+        assert_eq!(FatType::from_clusters(MIN_FAT32_CLUSTERS), FatType::Fat32);
+        assert!(FatType::Fat12.is_end_of_chain(0xFFF));
+        assert!(FatType::Fat16.is_bad(0xFFF7));
+    }
+}
@@ -0,0 +1,531 @@
+//! Consistency check of the FAT volume. fatfs trusts the on-disk structures
+//! completely, so the check walks the allocation table and directory tree
+//! itself. It runs with the volume unmounted and may take several calls.
+
+use std::cell::RefCell;
+
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::stable::{stable64_read, stable64_write};
+use ic_cdk_macros::update;
+
+use super::{
+    acl,
+    fat::{self, BootSector, Fat, FatType, RESERVED_FAT_ENTRIES},
+    is_mounted, mount_volume, unmount_volume, FsResult,
+};
+
+// Directory entries and clusters a single call may examine before it stops
+// and waits to be called again.
+const STEP_BUDGET: u64 = 200_000;
+
+const DIR_ENTRY_SIZE: usize = 32;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0F;
+const DELETED_ENTRY: u8 = 0xE5;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum FsckIssue {
+    FatMismatch {
+        fat: u32,
+    },
+    InvalidEntry {
+        path: String,
+        reason: String,
+    },
+    CrossLinked {
+        path: String,
+        cluster: u32,
+    },
+    SizeMismatch {
+        path: String,
+        size: u64,
+        allocated: u64,
+    },
+    LostClusters {
+        first: u32,
+        count: u32,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FsckReport {
+    pub done: bool,
+    pub repair: bool,
+    pub checked_entries: u64,
+    pub issues: Vec<FsckIssue>,
+}
+
+struct Bitmap(Vec<u64>);
+
+impl Bitmap {
+    fn new(len: u32) -> Self {
+        Bitmap(vec![0; (len as usize + 63) / 64])
+    }
+
+    fn get(&self, index: u32) -> bool {
+        self.0[index as usize / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn set(&mut self, index: u32, value: bool) {
+        let bit = 1 << (index % 64);
+        if value {
+            self.0[index as usize / 64] |= bit;
+        } else {
+            self.0[index as usize / 64] &= !bit;
+        }
+    }
+}
+
+// Where the entries of a directory are stored.
+enum DirLocation {
+    FixedRoot,
+    Clusters(Vec<u32>),
+}
+
+struct PendingDir {
+    path: String,
+    location: DirLocation,
+}
+
+enum Phase {
+    Tree,
+    LostClusters { next: u32 },
+}
+
+// Collects the long name entries that precede a short entry. They are
+// stored last part first, each holding 13 UCS-2 characters.
+#[derive(Default)]
+struct LongName(Vec<(u8, Vec<u16>)>);
+
+impl LongName {
+    fn push(&mut self, raw: &[u8]) {
+        let units = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30]
+            .iter()
+            .map(|&offset| fat::read_u16(raw, offset) as u16)
+            .collect();
+        self.0.push((raw[0] & 0x1F, units));
+    }
+
+    fn take(&mut self) -> Option<String> {
+        if self.0.is_empty() {
+            return None;
+        }
+
+        let mut parts = std::mem::take(&mut self.0);
+        parts.sort_by_key(|(order, _)| *order);
+        let units = parts
+            .into_iter()
+            .flat_map(|(_, units)| units)
+            .take_while(|&unit| unit != 0x0000 && unit != 0xFFFF)
+            .collect::<Vec<_>>();
+        Some(String::from_utf16_lossy(&units))
+    }
+}
+
+fn short_name(raw: &[u8]) -> String {
+    let mut base = raw[..8].to_vec();
+    // 0x05 stands in for a leading 0xE5, which marks deleted entries.
+    if base[0] == 0x05 {
+        base[0] = DELETED_ENTRY;
+    }
+
+    let base = String::from_utf8_lossy(&base).trim_end().to_string();
+    let extension = String::from_utf8_lossy(&raw[8..11]).trim_end().to_string();
+    if extension.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, extension)
+    }
+}
+
+struct FsckRun {
+    repair: bool,
+    boot_sector: BootSector,
+    fat_type: FatType,
+    fat: Fat,
+    // Whether `fat` has repairs that aren't written to the volume yet.
+    fat_dirty: bool,
+    used: Bitmap,
+    pending: Vec<PendingDir>,
+    phase: Phase,
+    steps: u64,
+    checked_entries: u64,
+    issues: Vec<FsckIssue>,
+}
+
+thread_local! {
+    static FSCK: RefCell<Option<FsckRun>> = RefCell::default();
+}
+
+impl FsckRun {
+    fn start(repair: bool) -> FsResult<Self> {
+        let boot_sector = BootSector::read()?;
+        let fat = Fat::read(&boot_sector, 0);
+        let mut issues = vec![];
+        for copy in 1..boot_sector.fats {
+            if Fat::read(&boot_sector, copy) != fat {
+                issues.push(FsckIssue::FatMismatch { fat: copy });
+            }
+        }
+
+        let mut run = FsckRun {
+            repair,
+            fat_type: boot_sector.fat_type(),
+            used: Bitmap::new(boot_sector.total_clusters() + RESERVED_FAT_ENTRIES),
+            boot_sector,
+            fat,
+            fat_dirty: false,
+            pending: vec![],
+            phase: Phase::Tree,
+            steps: 0,
+            checked_entries: 0,
+            issues,
+        };
+
+        let location = match run.fat_type {
+            FatType::Fat32 => {
+                DirLocation::Clusters(run.claim_chain("/", run.boot_sector.root_dir_cluster))
+            }
+            _ => DirLocation::FixedRoot,
+        };
+        run.pending.push(PendingDir {
+            path: String::new(),
+            location,
+        });
+        Ok(run)
+    }
+
+    // Follows the chain starting at `first` and claims its clusters. The walk
+    // stops at a cluster that is out of range, already claimed, free or bad;
+    // when repairing, the chain is ended right before it. Returns the
+    // clusters that remain part of the chain.
+    fn claim_chain(&mut self, path: &str, first: u32) -> Vec<u32> {
+        let mut chain = vec![];
+        let mut cluster = first;
+        loop {
+            self.steps += 1;
+
+            let issue = if !self.boot_sector.is_valid_cluster(cluster) {
+                Some(FsckIssue::InvalidEntry {
+                    path: path.to_string(),
+                    reason: format!("Chain points to invalid cluster {}", cluster),
+                })
+            } else if self.used.get(cluster) {
+                Some(FsckIssue::CrossLinked {
+                    path: path.to_string(),
+                    cluster,
+                })
+            } else {
+                None
+            };
+            if let Some(issue) = issue {
+                self.issues.push(issue);
+                if let (true, Some(&last)) = (self.repair, chain.last()) {
+                    self.repair_fat(last, self.fat_type.end_of_chain());
+                }
+                break;
+            }
+
+            self.used.set(cluster, true);
+            chain.push(cluster);
+
+            let next = self.fat.get(cluster);
+            if self.fat_type.is_end_of_chain(next) {
+                break;
+            }
+            if next == 0 || self.fat_type.is_bad(next) {
+                self.issues.push(FsckIssue::InvalidEntry {
+                    path: path.to_string(),
+                    reason: format!("Cluster {} links to free or bad cluster {}", cluster, next),
+                });
+                if self.repair {
+                    self.repair_fat(cluster, self.fat_type.end_of_chain());
+                }
+                break;
+            }
+            cluster = next;
+        }
+        chain
+    }
+
+    fn repair_fat(&mut self, cluster: u32, value: u32) {
+        self.fat.set(cluster, value);
+        self.fat_dirty = true;
+    }
+
+    fn release(&mut self, clusters: &[u32]) {
+        for &cluster in clusters {
+            self.repair_fat(cluster, 0);
+            self.used.set(cluster, false);
+        }
+    }
+
+    fn check_file(&mut self, path: &str, entry_offset: u64, first: u32, size: u32) {
+        let size = u64::from(size);
+        if first == 0 {
+            if size != 0 {
+                self.issues.push(FsckIssue::SizeMismatch {
+                    path: path.to_string(),
+                    size,
+                    allocated: 0,
+                });
+                if self.repair {
+                    write_entry_size(entry_offset, 0);
+                }
+            }
+            return;
+        }
+
+        let chain = self.claim_chain(path, first);
+        let cluster_size = self.boot_sector.cluster_size();
+        let allocated = chain.len() as u64 * cluster_size;
+        let needed = ((size + cluster_size - 1) / cluster_size) as usize;
+        if chain.is_empty() {
+            if self.repair {
+                write_entry_cluster(entry_offset, self.fat_type, 0);
+                write_entry_size(entry_offset, 0);
+            }
+            return;
+        }
+        if needed == chain.len() {
+            return;
+        }
+
+        self.issues.push(FsckIssue::SizeMismatch {
+            path: path.to_string(),
+            size,
+            allocated,
+        });
+        if !self.repair {
+            return;
+        }
+
+        if needed > chain.len() {
+            write_entry_size(entry_offset, allocated as u32);
+        } else if needed == 0 {
+            self.release(&chain);
+            write_entry_cluster(entry_offset, self.fat_type, 0);
+        } else {
+            self.repair_fat(chain[needed - 1], self.fat_type.end_of_chain());
+            self.release(&chain[needed..]);
+        }
+    }
+
+    fn check_dir(&mut self, path: String, entry_offset: u64, first: u32) {
+        let chain = if first == 0 {
+            self.issues.push(FsckIssue::InvalidEntry {
+                path: path.clone(),
+                reason: "Directory without clusters".to_string(),
+            });
+            vec![]
+        } else {
+            self.claim_chain(&path, first)
+        };
+
+        if !chain.is_empty() {
+            self.pending.push(PendingDir {
+                path,
+                location: DirLocation::Clusters(chain),
+            });
+        } else if self.repair {
+            stable64_write(entry_offset, &[DELETED_ENTRY]);
+        }
+    }
+
+    fn scan_dir(&mut self, dir: PendingDir) {
+        let regions = match &dir.location {
+            DirLocation::FixedRoot => vec![(
+                self.boot_sector.root_dir_offset(),
+                self.boot_sector.root_dir_size(),
+            )],
+            DirLocation::Clusters(clusters) => clusters
+                .iter()
+                .map(|&cluster| {
+                    (
+                        self.boot_sector.cluster_offset(cluster),
+                        self.boot_sector.cluster_size(),
+                    )
+                })
+                .collect(),
+        };
+
+        let mut long_name = LongName::default();
+        for (offset, len) in regions {
+            let mut bytes = vec![0; len as usize];
+            stable64_read(offset, &mut bytes);
+
+            for (index, raw) in bytes.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+                self.steps += 1;
+                match raw[0] {
+                    0x00 => return,
+                    DELETED_ENTRY => {
+                        long_name.take();
+                        continue;
+                    }
+                    _ => {}
+                }
+
+                let attributes = raw[11];
+                if attributes & ATTR_LONG_NAME == ATTR_LONG_NAME {
+                    long_name.push(raw);
+                    continue;
+                }
+
+                let name = long_name.take().unwrap_or_else(|| short_name(raw));
+                if attributes & ATTR_VOLUME_ID != 0 || raw[0] == b'.' {
+                    continue;
+                }
+
+                self.checked_entries += 1;
+                let path = format!("{}/{}", dir.path, name);
+                let entry_offset = offset + (index * DIR_ENTRY_SIZE) as u64;
+                let high = match self.fat_type {
+                    FatType::Fat32 => fat::read_u16(raw, 20),
+                    _ => 0,
+                };
+                let first = (high << 16) | fat::read_u16(raw, 26);
+
+                if attributes & ATTR_DIRECTORY != 0 {
+                    self.check_dir(path, entry_offset, first);
+                } else {
+                    self.check_file(&path, entry_offset, first, fat::read_u32(raw, 28));
+                }
+            }
+        }
+    }
+
+    // Clusters marked as allocated that no chain reached are lost.
+    fn scan_lost_clusters(&mut self, next: u32) -> Option<u32> {
+        let end = self.boot_sector.total_clusters() + RESERVED_FAT_ENTRIES;
+        let mut lost: Option<(u32, u32)> = None;
+        let mut cluster = next;
+
+        while cluster < end && self.steps < STEP_BUDGET {
+            self.steps += 1;
+            let value = self.fat.get(cluster);
+            if value != 0 && !self.fat_type.is_bad(value) && !self.used.get(cluster) {
+                lost = match lost {
+                    Some((first, count)) => Some((first, count + 1)),
+                    None => Some((cluster, 1)),
+                };
+                if self.repair {
+                    self.repair_fat(cluster, 0);
+                }
+            } else if let Some((first, count)) = lost.take() {
+                self.issues.push(FsckIssue::LostClusters { first, count });
+            }
+            cluster += 1;
+        }
+
+        if let Some((first, count)) = lost {
+            self.issues.push(FsckIssue::LostClusters { first, count });
+        }
+        if cluster < end {
+            Some(cluster)
+        } else {
+            None
+        }
+    }
+
+    /// Runs until the budget is used up. Returns true once the check is complete.
+    fn resume(&mut self) -> bool {
+        self.steps = 0;
+        while self.steps < STEP_BUDGET {
+            match self.phase {
+                Phase::Tree => match self.pending.pop() {
+                    Some(dir) => self.scan_dir(dir),
+                    None => {
+                        self.phase = Phase::LostClusters {
+                            next: RESERVED_FAT_ENTRIES,
+                        }
+                    }
+                },
+                Phase::LostClusters { next } => match self.scan_lost_clusters(next) {
+                    Some(next) => self.phase = Phase::LostClusters { next },
+                    None => return true,
+                },
+            }
+        }
+        false
+    }
+
+    // Writes the table repairs made so far to every FAT copy, after each
+    // call, so they land together with the directory entry repairs of the
+    // same call. The last call writes the table even without repairs to
+    // bring differing copies in line.
+    fn write_repairs(&mut self, done: bool) {
+        if !self.repair || !(self.fat_dirty || done) {
+            return;
+        }
+
+        for copy in 0..self.boot_sector.fats {
+            self.fat.write(&self.boot_sector, copy);
+        }
+        fat::invalidate_fs_info(&self.boot_sector);
+        self.fat_dirty = false;
+    }
+
+    fn report(&self, done: bool) -> FsckReport {
+        FsckReport {
+            done,
+            repair: self.repair,
+            checked_entries: self.checked_entries,
+            issues: self.issues.clone(),
+        }
+    }
+}
+
+fn write_entry_size(entry_offset: u64, size: u32) {
+    stable64_write(entry_offset + 28, &size.to_le_bytes());
+}
+
+fn write_entry_cluster(entry_offset: u64, fat_type: FatType, cluster: u32) {
+    if fat_type == FatType::Fat32 {
+        stable64_write(entry_offset + 20, &((cluster >> 16) as u16).to_le_bytes());
+    }
+    stable64_write(entry_offset + 26, &(cluster as u16).to_le_bytes());
+}
+
+/// Checks the allocation tables and directory tree for lost clusters,
+/// cross-linked chains, differing FAT copies and invalid entries, and fixes
+/// them when `repair` is set. Directory entry fixes are written as they are
+/// found, the repaired table is written to every FAT copy at the end of each
+/// call, so a check that is never finished leaves no half-applied repair.
+///
+/// The volume is unmounted while the check runs. Large volumes take several
+/// calls: repeat the call until `done` is true. Every report lists all issues
+/// found so far. Mounting the volume in between, or asking for a different
+/// `repair` mode, starts the check over.
+#[update]
+fn fsck(repair: bool) -> FsResult<FsckReport> {
+    acl::check_volume()?;
+    FSCK.with(|fsck| {
+        let mut fsck = fsck.borrow_mut();
+        let restart = match &*fsck {
+            Some(run) => run.repair != repair || is_mounted(),
+            None => true,
+        };
+        if restart {
+            unmount_volume()?;
+            *fsck = Some(FsckRun::start(repair).map_err(|error| {
+                let _ = mount_volume();
+                error
+            })?);
+        }
+
+        let run = fsck.as_mut().unwrap();
+        let done = run.resume();
+        run.write_repairs(done);
+        if !done {
+            return Ok(run.report(false));
+        }
+
+        let report = run.report(true);
+        *fsck = None;
+        // The report matters more than the mount result, which
+        // `volume_status` shows anyway.
+        let _ = mount_volume();
+        Ok(report)
+    })
+}
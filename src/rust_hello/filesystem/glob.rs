@@ -0,0 +1,212 @@
+use super::{FsError, FsResult};
+
+enum Token {
+    Char(char),
+    // `?`
+    Any,
+    // `*`, anything within one path component.
+    Star,
+    // `**`, anything including `/`.
+    DoubleStar,
+    // `**/`, any number of whole directories, including none.
+    Dirs,
+    // `[a-z]`, or `[!a-z]` when negated.
+    Class(Vec<(char, char)>, bool),
+}
+
+/// A shell-style pattern. Matching ignores case, like FAT names do.
+pub struct Glob {
+    tokens: Vec<Token>,
+    has_separator: bool,
+}
+
+fn lowercase(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> FsResult<Self> {
+        let invalid = || FsError::InvalidArgument(format!("Invalid glob: {}", pattern));
+        let mut tokens = vec![];
+        let mut chars = pattern.chars().map(lowercase).peekable();
+
+        while let Some(c) = chars.next() {
+            let token = match c {
+                '?' => Token::Any,
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        Token::Dirs
+                    } else {
+                        Token::DoubleStar
+                    }
+                }
+                '*' => Token::Star,
+                '[' => {
+                    let negated = matches!(chars.peek(), Some('!') | Some('^'));
+                    if negated {
+                        chars.next();
+                    }
+
+                    let mut ranges = vec![];
+                    loop {
+                        let start = match chars.next() {
+                            Some(']') if !ranges.is_empty() => break,
+                            Some(c) => c,
+                            None => return Err(invalid()),
+                        };
+                        if chars.peek() == Some(&'-') {
+                            chars.next();
+                            match chars.next() {
+                                Some(']') => {
+                                    ranges.push((start, start));
+                                    ranges.push(('-', '-'));
+                                    break;
+                                }
+                                Some(end) => ranges.push((start, end)),
+                                None => return Err(invalid()),
+                            }
+                        } else {
+                            ranges.push((start, start));
+                        }
+                    }
+                    Token::Class(ranges, negated)
+                }
+                '\\' => Token::Char(chars.next().ok_or_else(invalid)?),
+                c => Token::Char(c),
+            };
+            tokens.push(token);
+        }
+
+        Ok(Glob {
+            tokens,
+            has_separator: pattern.contains('/'),
+        })
+    }
+
+    /// Whether the pattern spans directories. Patterns without a `/` are
+    /// meant to be matched against bare names.
+    pub fn has_separator(&self) -> bool {
+        self.has_separator
+    }
+
+    pub fn matches(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().map(lowercase).collect();
+
+        // matched[j]: the tokens so far match the first j characters.
+        let mut matched = vec![false; text.len() + 1];
+        matched[0] = true;
+        for token in &self.tokens {
+            let mut next = vec![false; text.len() + 1];
+            match token {
+                Token::Star | Token::DoubleStar => {
+                    let crosses_dirs = matches!(token, Token::DoubleStar);
+                    for (j, &is_match) in matched.iter().enumerate() {
+                        next[j] = is_match
+                            || (j > 0 && next[j - 1] && (crosses_dirs || text[j - 1] != '/'));
+                    }
+                }
+                Token::Dirs => {
+                    let mut seen = false;
+                    for (j, &is_match) in matched.iter().enumerate() {
+                        next[j] = is_match || (seen && text[j - 1] == '/');
+                        seen |= is_match;
+                    }
+                }
+                _ => {
+                    for (j, &c) in text.iter().enumerate() {
+                        let accepts = match token {
+                            Token::Char(expected) => c == *expected,
+                            Token::Any => c != '/',
+                            Token::Class(ranges, negated) => {
+                                let in_class =
+                                    ranges.iter().any(|&(start, end)| start <= c && c <= end);
+                                c != '/' && in_class != *negated
+                            }
+                            _ => unreachable!(),
+                        };
+                        next[j + 1] = matched[j] && accepts;
+                    }
+                }
+            }
+            matched = next;
+        }
+
+        matched[text.len()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        Glob::new(pattern).unwrap().matches(text)
+    }
+
+    #[test]
+    fn star_stays_within_a_component() {
+        assert!(matches("*.txt", "notes.txt"));
+        assert!(matches("*.txt", ".txt"));
+        assert!(!matches("*.txt", "notes.txt.bak"));
+        assert!(!matches("*.txt", "docs/notes.txt"));
+        assert!(matches("docs/*", "docs/notes.txt"));
+        assert!(!matches("docs/*", "docs/old/notes.txt"));
+    }
+
+    #[test]
+    fn matching_ignores_case() {
+        assert!(matches("*.TXT", "notes.txt"));
+        assert!(matches("readme*", "README.md"));
+        assert!(matches("[a-c]*", "Beta"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_character() {
+        assert!(matches("a?c", "abc"));
+        assert!(!matches("a?c", "ac"));
+        assert!(!matches("a?c", "abbc"));
+        assert!(!matches("a?c", "a/c"));
+    }
+
+    #[test]
+    fn classes_and_negation() {
+        assert!(matches("file[0-9]", "file7"));
+        assert!(!matches("file[0-9]", "filex"));
+        assert!(matches("file[!0-9]", "filex"));
+        assert!(matches("file[^0-9]", "filex"));
+        assert!(!matches("file[!0-9]", "file7"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[a-]", "-"));
+        assert!(!matches("a[!x]b", "a/b"));
+    }
+
+    #[test]
+    fn double_star_spans_directories() {
+        assert!(matches("docs/**", "docs/a/b/notes.txt"));
+        assert!(matches("**.txt", "docs/a/notes.txt"));
+        assert!(matches("**/*.rs", "main.rs"));
+        assert!(matches("**/*.rs", "src/a/main.rs"));
+        assert!(matches("src/**/mod.rs", "src/mod.rs"));
+        assert!(matches("src/**/mod.rs", "src/a/b/mod.rs"));
+        assert!(!matches("src/**/mod.rs", "src/amod.rs"));
+        assert!(!matches("src/**/mod.rs", "srcx/mod.rs"));
+    }
+
+    #[test]
+    fn escapes_and_invalid_patterns() {
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "a"));
+        assert!(Glob::new("[abc").is_err());
+        assert!(Glob::new("[a-").is_err());
+        assert!(Glob::new("abc\\").is_err());
+    }
+
+    #[test]
+    fn separator_detection() {
+        assert!(!Glob::new("*.txt").unwrap().has_separator());
+        assert!(Glob::new("docs/*.txt").unwrap().has_separator());
+        assert!(Glob::new("**/x").unwrap().has_separator());
+    }
+}
@@ -0,0 +1,388 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::Read,
+};
+
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::{query, update};
+use serde_bytes::ByteBuf;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use super::{
+    acl::{self, Acls, Permission},
+    canonical, find_entry, open_dir_components, open_parent, path, system, timestamp, with_fs,
+    Change, Dir, DirEntry, FileSystem, FsError, FsResult,
+};
+
+// Marks the index as enabled. The entries themselves are split into
+// buckets below this system directory by a hash of their key, so a write
+// only rewrites the bucket of the written file.
+const INDEX_FILE: &str = "hash_index";
+const BUCKET_DIR: &str = "hash_buckets";
+const BUCKETS: u8 = 64;
+
+// Files up to this size are hashed by the call that writes them. Larger ones
+// would make every append rehash the whole file, so they are only marked
+// stale until `rehash_path` catches up.
+const SYNC_HASH_LIMIT: u64 = 4 * 1024 * 1024;
+
+// Bytes `verify_hashes` reads per call, to stay within the query instruction limit.
+const VERIFY_BUDGET: u64 = 64 * 1024 * 1024;
+
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha1,
+}
+
+/// What the hash index remembers about a file. `sha256` is `None` while a
+/// file too large to hash on every write is waiting for `rehash_path`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct FileHash {
+    pub path: String,
+    pub sha256: Option<ByteBuf>,
+    pub size: u64,
+    pub modified: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HashCheck {
+    pub checked: u64,
+    pub mismatched: Vec<String>,
+    pub missing: Vec<String>,
+    pub stale: Vec<String>,
+    pub next: Option<String>,
+}
+
+// Keyed by `key`, so lookups are case-insensitive like FAT names.
+type HashIndex = BTreeMap<String, FileHash>;
+
+fn key(components: &[String]) -> String {
+    path::join(components).to_uppercase()
+}
+
+fn bucket(key: &str) -> u8 {
+    Sha256::digest(key.as_bytes())[0] % BUCKETS
+}
+
+// The buckets that were read so far, and which of them need writing back.
+struct Index<'a> {
+    dir: Dir<'a>,
+    buckets: BTreeMap<u8, HashIndex>,
+    dirty: BTreeSet<u8>,
+}
+
+impl<'a> Index<'a> {
+    fn open(fs: &'a FileSystem) -> FsResult<Option<Index<'a>>> {
+        if system::load::<()>(fs, INDEX_FILE)?.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Index {
+            dir: system::dir(fs, &[BUCKET_DIR])?,
+            buckets: BTreeMap::new(),
+            dirty: BTreeSet::new(),
+        }))
+    }
+
+    fn require(fs: &'a FileSystem) -> FsResult<Index<'a>> {
+        Index::open(fs)?
+            .ok_or_else(|| FsError::InvalidArgument("The hash index is not enabled".to_string()))
+    }
+
+    fn bucket(&mut self, bucket: u8) -> FsResult<&mut HashIndex> {
+        if !self.buckets.contains_key(&bucket) {
+            let hashes: Vec<FileHash> =
+                system::load_from(&self.dir, &bucket.to_string())?.unwrap_or_default();
+            let hashes = hashes
+                .into_iter()
+                .map(|hash| (hash.path.to_uppercase(), hash))
+                .collect();
+            self.buckets.insert(bucket, hashes);
+        }
+        Ok(self.buckets.get_mut(&bucket).unwrap())
+    }
+
+    fn get(&mut self, components: &[String]) -> FsResult<Option<FileHash>> {
+        let key = key(components);
+        Ok(self.bucket(bucket(&key))?.get(&key).cloned())
+    }
+
+    fn insert(&mut self, hash: FileHash) -> FsResult<()> {
+        let key = hash.path.to_uppercase();
+        let bucket = bucket(&key);
+        self.bucket(bucket)?.insert(key, hash);
+        self.dirty.insert(bucket);
+        Ok(())
+    }
+
+    // Every entry, in key order. Reads all buckets.
+    fn all(&mut self) -> FsResult<HashIndex> {
+        let mut all = HashIndex::new();
+        for bucket in 0..BUCKETS {
+            all.extend(self.bucket(bucket)?.clone());
+        }
+        Ok(all)
+    }
+
+    // Takes out the entry at `components` and every one below it. Entries
+    // below a path can be in any bucket, so this reads all of them, but
+    // only writes back those that lost an entry.
+    fn remove_prefix(&mut self, components: &[String]) -> FsResult<Vec<FileHash>> {
+        let prefix = key(components);
+        let below = format!("{}/", prefix);
+        let mut removed = vec![];
+        for bucket in 0..BUCKETS {
+            let hashes = self.bucket(bucket)?;
+            let keys: Vec<String> = hashes
+                .keys()
+                .filter(|key| **key == prefix || key.starts_with(&below))
+                .cloned()
+                .collect();
+            if keys.is_empty() {
+                continue;
+            }
+            removed.extend(keys.iter().filter_map(|key| hashes.remove(key)));
+            self.dirty.insert(bucket);
+        }
+        Ok(removed)
+    }
+
+    fn store(&self) -> FsResult<()> {
+        for bucket in &self.dirty {
+            let hashes: Vec<&FileHash> = self.buckets[bucket].values().collect();
+            system::store_in(&self.dir, &bucket.to_string(), &hashes)?;
+        }
+        Ok(())
+    }
+}
+
+fn digest<D: Digest>(entry: &DirEntry) -> FsResult<Vec<u8>> {
+    let mut file = entry.to_file();
+    let mut hasher = D::new();
+    let mut buf = vec![0; HASH_BUFFER_SIZE];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+// Files larger than `limit` are left unhashed.
+fn file_hash(entry: &DirEntry, components: &[String], limit: u64) -> FsResult<FileHash> {
+    let sha256 = if entry.len() <= limit {
+        Some(ByteBuf::from(digest::<Sha256>(entry)?))
+    } else {
+        None
+    };
+    Ok(FileHash {
+        path: path::join(components),
+        sha256,
+        size: entry.len(),
+        modified: timestamp::to_nanos(entry.modified()),
+    })
+}
+
+// Hashes the file at `components` into the index, or every file below it
+// for a directory.
+fn rehash(index: &mut Index, entry: &DirEntry, components: &[String], limit: u64) -> FsResult<()> {
+    if entry.is_dir() {
+        rehash_dir(index, &entry.to_dir(), components, limit)
+    } else {
+        index.insert(file_hash(entry, components, limit)?)
+    }
+}
+
+fn rehash_dir(index: &mut Index, dir: &Dir, components: &[String], limit: u64) -> FsResult<()> {
+    for child in dir.iter() {
+        let child = child?;
+        let name = child.file_name();
+        if name != "." && name != ".." && !path::is_system(components, &name) {
+            let mut child_components = components.to_vec();
+            child_components.push(name);
+            rehash(index, &child, &child_components, limit)?;
+        }
+    }
+    Ok(())
+}
+
+fn lookup<'a>(fs: &'a FileSystem, components: &[String]) -> FsResult<Option<DirEntry<'a>>> {
+    match components.split_last() {
+        Some((name, parent)) => find_entry(&open_dir_components(fs, parent)?, name),
+        None => Ok(None),
+    }
+}
+
+/// Brings the hash index, if there is one, up to date with `changes`. Only
+/// the buckets that changed are written back.
+pub fn apply(fs: &FileSystem, changes: &[Change]) -> FsResult<()> {
+    let mut index = match Index::open(fs)? {
+        Some(index) => index,
+        None => return Ok(()),
+    };
+
+    for change in changes {
+        match change {
+            Change::Written(components) => {
+                if let Some(entry) = lookup(fs, components)? {
+                    rehash(&mut index, &entry, components, SYNC_HASH_LIMIT)?;
+                }
+            }
+            Change::Created(_) => {}
+            Change::Removed(components) => {
+                index.remove_prefix(components)?;
+            }
+            Change::Renamed(from, to) => {
+                for mut hash in index.remove_prefix(from)? {
+                    let mut components = to.clone();
+                    components.extend(path::normalize(&hash.path)?.into_iter().skip(from.len()));
+                    hash.path = path::join(&components);
+                    index.insert(hash)?;
+                }
+            }
+        }
+    }
+
+    index.store()
+}
+
+/// Hashes the contents of the file at `path`. SHA-1 is there for git
+/// interop, e.g. to compare against blob ids.
+#[query]
+fn hash_file(path: String, algo: HashAlgorithm) -> FsResult<ByteBuf> {
+    with_fs(|fs| {
+        acl::check_path(fs, &path, Permission::Read)?;
+        let (dir, name) = open_parent(fs, &path)?;
+        let entry = find_entry(&dir, &name)?.ok_or(FsError::NotFound)?;
+        if entry.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+
+        let digest = match algo {
+            HashAlgorithm::Sha256 => digest::<Sha256>(&entry)?,
+            HashAlgorithm::Sha1 => digest::<Sha1>(&entry)?,
+        };
+        Ok(ByteBuf::from(digest))
+    })
+}
+
+/// Turns the persisted hash index on or off. Once on, every write keeps the
+/// SHA-256 of the written file in the index, or marks it stale if it is
+/// over 4 MiB. Files that were there before are added with `rehash_path`.
+#[update]
+fn set_hash_index(enabled: bool) -> FsResult<()> {
+    with_fs(|fs| {
+        acl::check(fs, &[], Permission::Admin)?;
+        let enabled_before = Index::open(fs)?.is_some();
+        if enabled == enabled_before {
+            return Ok(());
+        }
+
+        // Buckets left over from an earlier time the index was on are out of
+        // date, so they go either way.
+        let dir = system::dir(fs, &[BUCKET_DIR])?;
+        for bucket in 0..BUCKETS {
+            if find_entry(&dir, &bucket.to_string())?.is_some() {
+                dir.remove(&bucket.to_string())?;
+            }
+        }
+        if enabled {
+            system::store(fs, INDEX_FILE, &())
+        } else {
+            system::remove(fs, INDEX_FILE)
+        }
+    })
+}
+
+/// Hashes `path`, or every file below it for a directory, into the index,
+/// however large. Also the way to accept a change reported by
+/// `verify_hashes`, and to fill in the hashes of stale files.
+#[update]
+fn rehash_path(path: String) -> FsResult<()> {
+    with_fs(|fs| {
+        let mut index = Index::require(fs)?;
+        let components = canonical(fs, &path)?;
+        acl::check(fs, &components, Permission::Write)?;
+        if components.is_empty() {
+            rehash_dir(&mut index, &fs.root_dir(), &components, u64::MAX)?;
+        } else {
+            let entry = lookup(fs, &components)?.ok_or(FsError::NotFound)?;
+            rehash(&mut index, &entry, &components, u64::MAX)?;
+        }
+        index.store()
+    })
+}
+
+/// The index entry for `path`: its hash, size and modification time as of
+/// the last write. Clients compare it against their copy instead of
+/// downloading the file.
+#[query]
+fn indexed_hash(path: String) -> FsResult<Option<FileHash>> {
+    with_fs(|fs| {
+        let components = canonical(fs, &path)?;
+        acl::check(fs, &components, Permission::Read)?;
+        Index::require(fs)?.get(&components)
+    })
+}
+
+/// Rehashes indexed files in path order, starting after `after`, and
+/// reports those whose contents no longer match the index. Files that were
+/// written through the canister always match, so a mismatch means the data
+/// changed underneath. Stale files are listed without being read. Continue
+/// with `next` until it is empty.
+#[query]
+fn verify_hashes(after: Option<String>, limit: u32) -> FsResult<HashCheck> {
+    with_fs(|fs| {
+        acl::check(fs, &[], Permission::Read)?;
+        let acls = Acls::load(fs)?;
+        let caller = ic_cdk::caller();
+        let index = Index::require(fs)?.all()?;
+
+        let mut check = HashCheck {
+            checked: 0,
+            mismatched: vec![],
+            missing: vec![],
+            stale: vec![],
+            next: None,
+        };
+        let start = after.map(|after| after.to_uppercase());
+        let mut budget = VERIFY_BUDGET;
+        let mut remaining = index
+            .iter()
+            .filter(|(key, _)| start.as_ref().map_or(true, |start| *key > start))
+            .peekable();
+        while let Some((_, hash)) = remaining.next() {
+            // Files the caller can't read are left out, not even named.
+            let components = path::normalize(&hash.path)?;
+            if !acls.allows(&components, caller, Permission::Read) {
+                continue;
+            }
+            check.checked += 1;
+
+            match (lookup(fs, &components)?, &hash.sha256) {
+                (Some(entry), _) if entry.is_dir() => check.missing.push(hash.path.clone()),
+                (Some(_), None) => check.stale.push(hash.path.clone()),
+                (Some(entry), Some(sha256)) => {
+                    budget = budget.saturating_sub(entry.len());
+                    if digest::<Sha256>(&entry)? != sha256.as_slice() {
+                        check.mismatched.push(hash.path.clone());
+                    }
+                }
+                (None, _) => check.missing.push(hash.path.clone()),
+            }
+
+            if check.checked >= u64::from(limit) || budget == 0 {
+                if remaining.peek().is_some() {
+                    check.next = Some(hash.path.clone());
+                }
+                break;
+            }
+        }
+        Ok(check)
+    })
+}
@@ -0,0 +1,499 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+};
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::{query, update};
+
+use super::{
+    acl::{self, Acls, Permission},
+    eq_name, find_entry, open_dir_components, path, record, system, trash, with_fs, Change, Dir,
+    DirEntry, FileSystem, FsError, FsResult,
+};
+
+/// Directory below the root that holds the home directories, one per
+/// principal and named after its textual form.
+pub const HOME_DIR: &str = "home";
+
+const QUOTA_FILE: &str = "quotas";
+
+// What a home directory holds, kept up to date by `apply` so that quota
+// checks don't walk the whole home on every write.
+#[derive(Default)]
+struct Usage {
+    // The length of every entry below the home, 0 for directories, keyed by
+    // its uppercased path components.
+    sizes: BTreeMap<Vec<String>, u64>,
+    bytes: u64,
+    // What the current call is about to write, until `apply` records it: the
+    // length each path will have and the growth that adds up to.
+    reserved: BTreeMap<Vec<String>, u64>,
+    reserved_bytes: u64,
+    reserved_files: u64,
+    // Bytes and entries deleted from the home that are still in the owner's
+    // trash, loaded from the trash index when None.
+    trashed: Option<(u64, u64)>,
+}
+
+impl Usage {
+    fn files(&self) -> u64 {
+        self.sizes.len() as u64
+    }
+
+    // What `key` and everything below it hold, in bytes and entries.
+    fn held(&self, key: &[String]) -> (u64, u64) {
+        self.sizes
+            .range(key.to_vec()..)
+            .take_while(|(path, _)| path.starts_with(key))
+            .fold((0, 0), |(bytes, files), (_, len)| (bytes + len, files + 1))
+    }
+
+    fn insert(&mut self, key: Vec<String>, len: u64) {
+        self.bytes += len;
+        if let Some(old) = self.sizes.insert(key, len) {
+            self.bytes -= old;
+        }
+    }
+
+    // Forgets `key` and everything below it.
+    fn remove_tree(&mut self, key: &[String]) {
+        let bytes = &mut self.bytes;
+        self.sizes.retain(|path, len| {
+            let below = path.starts_with(key);
+            if below {
+                *bytes -= *len;
+            }
+            !below
+        });
+    }
+
+    // Adds `entry` at `components`, which lies `depth` components below the
+    // home, and everything below it, along with its parents.
+    fn add_entry(&mut self, entry: &DirEntry, components: &[String], depth: usize) -> FsResult<()> {
+        let key = usage_key(components);
+        for end in key.len() - depth + 1..key.len() {
+            self.sizes.entry(key[..end].to_vec()).or_insert(0);
+        }
+        if entry.is_dir() {
+            self.insert(key, 0);
+            self.add_dir(&entry.to_dir(), components)
+        } else {
+            self.insert(key, entry.len());
+            Ok(())
+        }
+    }
+
+    fn add_dir(&mut self, dir: &Dir, components: &[String]) -> FsResult<()> {
+        for entry in dir.iter() {
+            let entry = entry?;
+            let name = entry.file_name();
+            if name == "." || name == ".." {
+                continue;
+            }
+
+            let mut child = components.to_vec();
+            child.push(name);
+            self.add_entry(&entry, &child, 1)?;
+        }
+        Ok(())
+    }
+}
+
+thread_local! {
+    static USAGE: RefCell<BTreeMap<Principal, Usage>> = RefCell::default();
+    // Homes the current call reserved growth in but hasn't recorded yet.
+    static PENDING: RefCell<BTreeSet<Principal>> = RefCell::default();
+}
+
+/// Limits on what a home directory may hold. Every file and directory
+/// below it counts towards `max_files`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Quota {
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct PrincipalQuota {
+    principal: Principal,
+    quota: Quota,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+struct QuotaConfig {
+    default: Option<Quota>,
+    principals: Vec<PrincipalQuota>,
+}
+
+impl QuotaConfig {
+    fn quota(&self, principal: Principal) -> Option<&Quota> {
+        self.principals
+            .iter()
+            .find(|entry| entry.principal == principal)
+            .map(|entry| &entry.quota)
+            .or_else(|| self.default.as_ref())
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct QuotaUsage {
+    pub principal: Principal,
+    pub home: String,
+    pub bytes: u64,
+    pub files: u64,
+    // What the owner's trash holds from the home, which counts against the
+    // quota as well.
+    pub trashed_bytes: u64,
+    pub trashed_files: u64,
+    pub quota: Option<Quota>,
+}
+
+fn load_config(fs: &FileSystem) -> FsResult<QuotaConfig> {
+    Ok(system::load(fs, QUOTA_FILE)?.unwrap_or_default())
+}
+
+pub fn home_components(principal: Principal) -> Vec<String> {
+    vec![HOME_DIR.to_string(), principal.to_text()]
+}
+
+/// The principal whose home directory `components` lies in. They have to be
+/// canonical, see `canonical`, or an 8.3 alias of the home would name nobody.
+pub fn home_owner(components: &[String]) -> Option<Principal> {
+    match components {
+        [home, name, ..] if eq_name(home, HOME_DIR) => {
+            Principal::from_text(name.to_lowercase()).ok()
+        }
+        _ => None,
+    }
+}
+
+fn open_or_create<'a>(dir: &Dir<'a>, name: &str) -> FsResult<(Dir<'a>, bool)> {
+    match find_entry(dir, name)? {
+        Some(entry) if entry.is_dir() => Ok((entry.to_dir(), false)),
+        Some(_) => Err(FsError::NotADirectory),
+        None => Ok((dir.create_dir(name)?, true)),
+    }
+}
+
+/// Whether `components` lies in the caller's home directory.
+pub fn is_own(components: &[String]) -> bool {
+    let caller = ic_cdk::caller();
+    caller != Principal::anonymous() && home_owner(components) == Some(caller)
+}
+
+/// Creates the caller's home directory if `components` lies inside it and it
+/// doesn't exist yet. The caller owns it and nobody else gets any access,
+/// unless it already has an ACL of its own. Endpoints that write call this
+/// once the caller passed `acl::check`, before they touch the path.
+pub fn ensure(fs: &FileSystem, components: &[String]) -> FsResult<()> {
+    if !is_own(components) {
+        return Ok(());
+    }
+
+    let caller = ic_cdk::caller();
+    let home = home_components(caller);
+    let (homes, created_homes) = open_or_create(&fs.root_dir(), HOME_DIR)?;
+    let (_, created) = open_or_create(&homes, &home[1])?;
+    acl::init_owner(fs, &home, caller)?;
+
+    let mut changes = vec![];
+    if created_homes {
+        changes.push(Change::Created(home[..1].to_vec()));
+    }
+    if created {
+        changes.push(Change::Created(home));
+    }
+    record(fs, &changes);
+    Ok(())
+}
+
+// The entry at `components`, or None if it or its parent is gone.
+fn find<'a>(fs: &'a FileSystem, components: &[String]) -> FsResult<Option<DirEntry<'a>>> {
+    let (name, parent) = match components.split_last() {
+        Some(split) => split,
+        None => return Ok(None),
+    };
+    match open_dir_components(fs, parent) {
+        Ok(dir) => find_entry(&dir, name),
+        Err(FsError::NotFound) | Err(FsError::NotADirectory) => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+fn usage_key(components: &[String]) -> Vec<String> {
+    components.iter().map(|name| name.to_uppercase()).collect()
+}
+
+// Runs `f` on the cached usage of `principal`'s home, walking the home first
+// if it isn't cached yet.
+fn with_usage<T>(
+    fs: &FileSystem,
+    principal: Principal,
+    f: impl FnOnce(&mut Usage) -> T,
+) -> FsResult<T> {
+    USAGE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        if !cache.contains_key(&principal) {
+            let home = home_components(principal);
+            let mut usage = Usage::default();
+            if let Some(entry) = find(fs, &home)?.filter(|entry| entry.is_dir()) {
+                usage.add_dir(&entry.to_dir(), &home)?;
+            }
+            cache.insert(principal, usage);
+        }
+        let usage = cache.get_mut(&principal).unwrap();
+        if usage.trashed.is_none() {
+            usage.trashed = Some(trash::charged(fs, principal)?);
+        }
+        Ok(f(usage))
+    })
+}
+
+// Fails with `QuotaExceeded` unless the home `components` lies in has room
+// for the entry there to be `len` bytes long, for it and its parents where
+// they don't exist yet, and for `extra` more bytes and entries below it.
+fn reserve(fs: &FileSystem, components: &[String], len: u64, extra: (u64, u64)) -> FsResult<()> {
+    let owner = match home_owner(components) {
+        Some(owner) => owner,
+        None => return Ok(()),
+    };
+    let quota = match load_config(fs)?.quota(owner) {
+        Some(quota) => quota.clone(),
+        None => return Ok(()),
+    };
+
+    with_usage(fs, owner, |usage| {
+        let key = usage_key(components);
+        let known = |key: &[String]| usage.reserved.get(key).or_else(|| usage.sizes.get(key));
+        let size = known(&key).copied().unwrap_or(0);
+        let created: Vec<Vec<String>> = (3..=key.len())
+            .map(|end| key[..end].to_vec())
+            .filter(|key| known(key).is_none())
+            .collect();
+
+        let bytes = len.saturating_sub(size).saturating_add(extra.0);
+        let files = (created.len() as u64).saturating_add(extra.1);
+        let (trashed_bytes, trashed_files) = usage.trashed.unwrap_or_default();
+        let projected_bytes = usage
+            .bytes
+            .saturating_add(trashed_bytes)
+            .saturating_add(usage.reserved_bytes)
+            .saturating_add(bytes);
+        let projected_files = usage
+            .files()
+            .saturating_add(trashed_files)
+            .saturating_add(usage.reserved_files)
+            .saturating_add(files);
+        if quota.max_bytes.map_or(false, |max| projected_bytes > max)
+            || quota.max_files.map_or(false, |max| projected_files > max)
+        {
+            return Err(FsError::QuotaExceeded);
+        }
+
+        usage.reserved_bytes = usage.reserved_bytes.saturating_add(bytes);
+        usage.reserved_files = usage.reserved_files.saturating_add(files);
+        for key in created {
+            usage.reserved.insert(key, 0);
+        }
+        usage.reserved.insert(key, size.max(len));
+        PENDING.with(|pending| pending.borrow_mut().insert(owner));
+        Ok(())
+    })?
+}
+
+/// Checks the quota before the file at `components` is written to be `len`
+/// bytes long, creating it and its parents if needed.
+pub fn reserve_file(fs: &FileSystem, components: &[String], len: u64) -> FsResult<()> {
+    reserve(fs, components, len, (0, 0))
+}
+
+/// Checks the quota before the directory at `components` is created along
+/// with its parents.
+pub fn reserve_dir(fs: &FileSystem, components: &[String]) -> FsResult<()> {
+    reserve(fs, components, 0, (0, 0))
+}
+
+/// Checks the quota before `entry`, which lies at `from`, is copied or moved
+/// to `to`. Moves within one home don't change its usage.
+pub fn reserve_tree(
+    fs: &FileSystem,
+    from: Option<&[String]>,
+    to: &[String],
+    entry: &DirEntry,
+) -> FsResult<()> {
+    if from.map_or(false, |from| home_owner(from) == home_owner(to)) {
+        return Ok(());
+    }
+    if !entry.is_dir() {
+        return reserve(fs, to, entry.len(), (0, 0));
+    }
+
+    let mut tree = Usage::default();
+    tree.add_dir(&entry.to_dir(), to)?;
+    reserve(fs, to, 0, (tree.bytes, tree.files()))
+}
+
+/// What the entry at `components` and everything below it hold in bytes and
+/// entries, if it lies in a home.
+pub fn held(fs: &FileSystem, components: &[String]) -> FsResult<Option<(u64, u64)>> {
+    match home_owner(components) {
+        Some(owner) => with_usage(fs, owner, |usage| Some(usage.held(&usage_key(components)))),
+        None => Ok(None),
+    }
+}
+
+/// Makes the next quota check of `owner`'s home count their trash afresh,
+/// for when the trash index changed.
+pub fn forget_trashed(owner: Principal) {
+    USAGE.with(|cache| {
+        if let Some(usage) = cache.borrow_mut().get_mut(&owner) {
+            usage.trashed = None;
+        }
+    });
+}
+
+/// Brings the cached usage of the homes `changes` touched up to date, which
+/// also settles what the call reserved.
+pub fn apply(fs: &FileSystem, changes: &[Change]) -> FsResult<()> {
+    let mut touched = vec![];
+    for change in changes {
+        match change {
+            Change::Written(components)
+            | Change::Created(components)
+            | Change::Removed(components) => touched.push(components),
+            Change::Renamed(from, to) => {
+                touched.push(from);
+                touched.push(to);
+            }
+        }
+    }
+
+    USAGE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        for components in touched {
+            // Changes to a home itself or the directory holding them are
+            // rare enough to just walk again.
+            if components.len() <= 2 {
+                if let Some(owner) = home_owner(components) {
+                    cache.remove(&owner);
+                } else if components
+                    .first()
+                    .map_or(false, |name| eq_name(name, HOME_DIR))
+                {
+                    cache.clear();
+                }
+                continue;
+            }
+
+            let usage = match home_owner(components).and_then(|owner| cache.get_mut(&owner)) {
+                Some(usage) => usage,
+                None => continue,
+            };
+            usage.remove_tree(&usage_key(components));
+            if let Some(entry) = find(fs, components)? {
+                usage.add_entry(&entry, components, components.len() - 2)?;
+            }
+        }
+
+        for usage in cache.values_mut() {
+            usage.reserved.clear();
+            usage.reserved_bytes = 0;
+            usage.reserved_files = 0;
+        }
+        Ok(())
+    })?;
+    PENDING.with(|pending| pending.borrow_mut().clear());
+    Ok(())
+}
+
+/// Drops the cached usage of homes the call reserved growth in without
+/// recording it, as whatever it wrote is unaccounted for. Called once each
+/// call is done with the volume.
+pub fn settle() {
+    let pending = PENDING.with(|pending| std::mem::take(&mut *pending.borrow_mut()));
+    USAGE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        for owner in pending {
+            cache.remove(&owner);
+        }
+    });
+}
+
+/// Drops all cached usage, for when the volume is unmounted and may come
+/// back with other contents.
+pub fn forget_usage() {
+    USAGE.with(|cache| cache.borrow_mut().clear());
+}
+
+/// The caller's home directory, created on first use.
+#[update]
+fn home() -> FsResult<String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(FsError::PermissionDenied);
+    }
+
+    with_fs(|fs| {
+        let home = home_components(caller);
+        ensure(fs, &home)?;
+        Ok(path::join(&home))
+    })
+}
+
+/// Sets the quota of `principal`'s home directory, or with `None` makes the
+/// default quota apply to it again. Needs `Admin` on the root.
+#[update]
+fn set_quota(principal: Principal, quota: Option<Quota>) -> FsResult<()> {
+    with_fs(|fs| {
+        acl::check(fs, &[], Permission::Admin)?;
+        let mut config = load_config(fs)?;
+        config
+            .principals
+            .retain(|entry| entry.principal != principal);
+        if let Some(quota) = quota {
+            config.principals.push(PrincipalQuota { principal, quota });
+        }
+        system::store(fs, QUOTA_FILE, &config)
+    })
+}
+
+/// Sets the quota for principals without one of their own. Needs `Admin` on
+/// the root.
+#[update]
+fn set_default_quota(quota: Option<Quota>) -> FsResult<()> {
+    with_fs(|fs| {
+        acl::check(fs, &[], Permission::Admin)?;
+        let mut config = load_config(fs)?;
+        config.default = quota;
+        system::store(fs, QUOTA_FILE, &config)
+    })
+}
+
+/// What `principal`'s home directory and the part of their trash deleted
+/// from it hold, and the quota that applies to both together. Needs read
+/// access to the home directory.
+#[query]
+fn quota_usage(principal: Principal) -> FsResult<QuotaUsage> {
+    with_fs(|fs| {
+        let home = home_components(principal);
+        Acls::load(fs)?.check(&home, Permission::Read)?;
+
+        let (bytes, files, (trashed_bytes, trashed_files)) = with_usage(fs, principal, |usage| {
+            (
+                usage.bytes,
+                usage.files(),
+                usage.trashed.unwrap_or_default(),
+            )
+        })?;
+        Ok(QuotaUsage {
+            principal,
+            home: path::join(&home),
+            bytes,
+            files,
+            trashed_bytes,
+            trashed_files,
+            quota: load_config(fs)?.quota(principal).cloned(),
+        })
+    })
+}
@@ -0,0 +1,217 @@
+use std::io::Read;
+
+use candid::{CandidType, Deserialize, Principal};
+use fatfs::{Seek, SeekFrom, Write};
+use ic_cdk_macros::query;
+
+use super::{
+    acl::{Acls, Permission},
+    find_entry, path, system, with_fs, Change, Dir, FileSystem, FsError, FsResult,
+};
+
+const JOURNAL_FILE: &str = "journal";
+
+// Entries live in segments below this system directory, so a change only
+// appends to the newest one instead of rewriting the whole journal.
+const SEGMENT_DIR: &str = "journal_segments";
+
+// Segment `n` holds the entries with sequence numbers from
+// `n * SEGMENT_SIZE + 1` to `(n + 1) * SEGMENT_SIZE`.
+const SEGMENT_SIZE: u64 = 1_000;
+
+// Older segments are dropped once this many full ones precede the newest,
+// which keeps at least 10_000 entries around.
+const KEPT_SEGMENTS: u64 = 10;
+
+// Upper bound for `limit` in `changes_since`, to stay within the response size.
+const MAX_PAGE_SIZE: u32 = 1_000;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ChangeKind {
+    Written,
+    Created,
+    Removed,
+    Renamed,
+}
+
+/// A journaled change. `to` is where a renamed path went. `timestamp` is in
+/// nanoseconds since the epoch.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct JournalEntry {
+    pub seq: u64,
+    pub timestamp: u64,
+    pub caller: Principal,
+    pub kind: ChangeKind,
+    pub path: String,
+    pub to: Option<String>,
+}
+
+/// A page of the journal. Pass `last_seq` back to `changes_since` to poll
+/// for what follows. `truncated` means entries after the requested sequence
+/// number were already dropped, so the client has to rescan.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ChangePage {
+    pub entries: Vec<JournalEntry>,
+    pub last_seq: u64,
+    pub truncated: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+struct JournalHead {
+    // Sequence number of the latest entry; the first one is 1.
+    last_seq: u64,
+}
+
+fn load(fs: &FileSystem) -> FsResult<JournalHead> {
+    Ok(system::load(fs, JOURNAL_FILE)?.unwrap_or_default())
+}
+
+fn segment(seq: u64) -> u64 {
+    seq.saturating_sub(1) / SEGMENT_SIZE
+}
+
+// The segments on the volume, oldest first.
+fn segments(dir: &Dir) -> FsResult<Vec<u64>> {
+    let mut segments = vec![];
+    for entry in dir.iter() {
+        if let Ok(segment) = entry?.file_name().parse() {
+            segments.push(segment);
+        }
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+// Each entry is stored as its candid encoding, prefixed with its length as
+// a little-endian u32.
+fn encode(entry: &JournalEntry) -> FsResult<Vec<u8>> {
+    let encoded = candid::encode_one(entry).map_err(|error| FsError::Io(error.to_string()))?;
+    let mut bytes = (encoded.len() as u32).to_le_bytes().to_vec();
+    bytes.extend(encoded);
+    Ok(bytes)
+}
+
+fn read_segment(dir: &Dir, segment: u64) -> FsResult<Vec<JournalEntry>> {
+    let mut bytes = vec![];
+    match find_entry(dir, &segment.to_string())? {
+        Some(entry) => entry.to_file().read_to_end(&mut bytes)?,
+        None => return Ok(vec![]),
+    };
+
+    let corrupt = || FsError::Io(format!("Corrupt journal segment {}", segment));
+    let mut entries = vec![];
+    let mut rest = &bytes[..];
+    while !rest.is_empty() {
+        if rest.len() < 4 {
+            return Err(corrupt());
+        }
+        let (len, tail) = rest.split_at(4);
+        let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        if tail.len() < len {
+            return Err(corrupt());
+        }
+        let (encoded, tail) = tail.split_at(len);
+        entries.push(candid::decode_one(encoded).map_err(|_| corrupt())?);
+        rest = tail;
+    }
+    Ok(entries)
+}
+
+/// Appends `changes` to the journal. Only the newest segment and the small
+/// head file are written; segments that fall out of the kept range are
+/// deleted when a new one starts.
+pub fn apply(fs: &FileSystem, changes: &[Change]) -> FsResult<()> {
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    let mut head = load(fs)?;
+    let dir = system::dir(fs, &[SEGMENT_DIR])?;
+    let timestamp = ic_cdk::api::time();
+    let caller = ic_cdk::caller();
+    for change in changes {
+        let (kind, components, to) = match change {
+            Change::Written(components) => (ChangeKind::Written, components, None),
+            Change::Created(components) => (ChangeKind::Created, components, None),
+            Change::Removed(components) => (ChangeKind::Removed, components, None),
+            Change::Renamed(from, to) => (ChangeKind::Renamed, from, Some(path::join(to))),
+        };
+
+        head.last_seq = head.last_seq.saturating_add(1);
+        let entry = JournalEntry {
+            seq: head.last_seq,
+            timestamp,
+            caller,
+            kind,
+            path: path::join(components),
+            to,
+        };
+
+        let current = segment(entry.seq);
+        let name = current.to_string();
+        let mut file = match find_entry(&dir, &name)? {
+            Some(entry) => entry.to_file(),
+            None => {
+                for old in segments(&dir)? {
+                    if old + KEPT_SEGMENTS < current {
+                        dir.remove(&old.to_string())?;
+                    }
+                }
+                dir.create_file(&name)?
+            }
+        };
+        file.seek(SeekFrom::End(0))?;
+        file.write_all(&encode(&entry)?)?;
+        file.flush()?;
+    }
+
+    system::store(fs, JOURNAL_FILE, &head)
+}
+
+/// Up to `limit` journal entries after sequence number `seq`, oldest first.
+/// Start with 0. Changes to paths the caller can't read are left out, but
+/// still advance `last_seq`.
+#[query]
+fn changes_since(seq: u64, limit: u32) -> FsResult<ChangePage> {
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+
+    with_fs(|fs| {
+        let head = load(fs)?;
+        let dir = system::dir(fs, &[SEGMENT_DIR])?;
+        let acls = Acls::load(fs)?;
+        let caller = ic_cdk::caller();
+        let readable = |path: &str| {
+            path::normalize(path).map_or(false, |components| {
+                acls.allows(&components, caller, Permission::Read)
+            })
+        };
+
+        // Entries after `seq` are gone if the segment holding the next one
+        // was dropped, or was never written because the journal is older
+        // than its segments.
+        let next = seq.saturating_add(1);
+        let kept = segments(&dir)?;
+        let mut page = ChangePage {
+            entries: vec![],
+            last_seq: seq.min(head.last_seq),
+            truncated: seq < head.last_seq
+                && kept.first().map_or(true, |&oldest| oldest > segment(next)),
+        };
+        for current in kept.into_iter().filter(|&current| current >= segment(next)) {
+            for entry in read_segment(&dir, current)? {
+                if entry.seq <= seq {
+                    continue;
+                }
+                if page.entries.len() == limit {
+                    return Ok(page);
+                }
+
+                page.last_seq = entry.seq;
+                if readable(&entry.path) || entry.to.as_deref().map_or(false, readable) {
+                    page.entries.push(entry);
+                }
+            }
+        }
+        Ok(page)
+    })
+}
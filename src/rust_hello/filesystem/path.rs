@@ -0,0 +1,139 @@
+use super::{eq_name, FsError, FsResult};
+
+/// Root directory that holds the filesystem's own metadata. Paths into it
+/// are rejected and listings leave it out.
+pub const SYSTEM_DIR: &str = ".system";
+
+/// Whether `name` in the directory at `dir_path` is the system directory.
+pub fn is_system(dir_path: &[String], name: &str) -> bool {
+    dir_path.is_empty() && eq_name(name, SYSTEM_DIR)
+}
+
+/// Splits `path` into its components below the volume root.
+///
+/// `/foo`, `./foo` and `foo` all name the same entry. Repeated and trailing
+/// separators and `.` components are ignored and `..` steps back one level;
+/// a path that would climb above the root or into the system directory is
+/// rejected.
+pub fn normalize(path: &str) -> FsResult<Vec<String>> {
+    let mut components: Vec<String> = vec![];
+
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop().ok_or(FsError::InvalidPath)?;
+            }
+            name => components.push(name.to_string()),
+        }
+    }
+
+    if components
+        .first()
+        .map_or(false, |name| is_system(&[], name))
+    {
+        return Err(FsError::InvalidPath);
+    }
+    Ok(components)
+}
+
+/// Replaces each of `components` with the name `long_name` returns for it,
+/// given the components before it, which are replaced already. Once it
+/// returns None, e.g. for an entry that doesn't exist yet, the rest is kept
+/// as it is.
+pub fn canonicalize(
+    mut components: Vec<String>,
+    mut long_name: impl FnMut(&[String], &str) -> FsResult<Option<String>>,
+) -> FsResult<Vec<String>> {
+    for index in 0..components.len() {
+        match long_name(&components[..index], &components[index])? {
+            Some(name) => components[index] = name,
+            None => break,
+        }
+    }
+    Ok(components)
+}
+
+/// Normalizes `path` and splits off its final component. The root itself has
+/// no final component and is rejected.
+pub fn split_last(path: &str) -> FsResult<(Vec<String>, String)> {
+    let mut components = normalize(path)?;
+    let name = components.pop().ok_or(FsError::InvalidPath)?;
+    Ok((components, name))
+}
+
+/// Formats normalized components back into an absolute path.
+pub fn join(components: &[String]) -> String {
+    format!("/{}", components.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owned(names: &[&str]) -> FsResult<Vec<String>> {
+        Ok(names.iter().map(|name| name.to_string()).collect())
+    }
+
+    #[test]
+    fn normalize_accepts_equivalent_spellings() {
+        for path in &[
+            "/foo/bar",
+            "foo/bar",
+            "./foo/bar",
+            "//foo//bar/",
+            "/foo/./bar/.",
+        ] {
+            assert_eq!(normalize(path), owned(&["foo", "bar"]), "{}", path);
+        }
+    }
+
+    #[test]
+    fn normalize_steps_back_on_parent_components() {
+        assert_eq!(normalize("/foo/../bar"), owned(&["bar"]));
+        assert_eq!(normalize("/foo/bar/.."), owned(&["foo"]));
+        assert_eq!(normalize("/foo/.."), owned(&[]));
+    }
+
+    #[test]
+    fn normalize_maps_the_root_to_no_components() {
+        for path in &["", "/", ".", "//./"] {
+            assert_eq!(normalize(path), owned(&[]), "{}", path);
+        }
+    }
+
+    #[test]
+    fn normalize_rejects_climbing_above_the_root() {
+        assert_eq!(normalize(".."), Err(FsError::InvalidPath));
+        assert_eq!(normalize("/foo/../../bar"), Err(FsError::InvalidPath));
+    }
+
+    #[test]
+    fn normalize_rejects_the_system_directory() {
+        assert_eq!(normalize("/.system"), Err(FsError::InvalidPath));
+        assert_eq!(normalize("/.SYSTEM/acl"), Err(FsError::InvalidPath));
+        assert_eq!(normalize("/foo/../.system"), Err(FsError::InvalidPath));
+        assert_eq!(normalize("/foo/.system"), owned(&["foo", ".system"]));
+    }
+
+    #[test]
+    fn split_last_and_join() {
+        assert_eq!(
+            split_last("/foo/bar"),
+            Ok((vec!["foo".to_string()], "bar".to_string()))
+        );
+        assert_eq!(split_last("/"), Err(FsError::InvalidPath));
+        assert_eq!(join(&normalize("foo//bar/").unwrap()), "/foo/bar");
+        assert_eq!(join(&[]), "/");
+    }
+
+    #[test]
+    fn canonicalize_keeps_the_rest_after_a_missing_entry() {
+        let long_name = |dir: &[String], name: &str| -> FsResult<Option<String>> {
+            Ok((dir.is_empty() && name.eq_ignore_ascii_case("DOCUME~1"))
+                .then(|| "Documents".to_string()))
+        };
+        let components = canonicalize(owned(&["docume~1", "NEW~1", "x"]).unwrap(), long_name);
+        assert_eq!(components, owned(&["Documents", "NEW~1", "x"]));
+    }
+}
@@ -0,0 +1,356 @@
+use std::io::{BufRead, BufReader, Read};
+
+use candid::{CandidType, Deserialize};
+use fatfs::{Seek, SeekFrom};
+use ic_cdk_macros::query;
+use regex::Regex;
+
+use super::{
+    acl::{Acls, Permission},
+    canonical, find_entry,
+    glob::Glob,
+    open_dir_components, path, stat, text, timestamp,
+    tree::{walk_after, Visit, WalkEntry},
+    with_fs, DirEntry, FsError, FsResult,
+};
+
+// Work a single call may do before it stops and hands out a cursor, so big
+// volumes don't run into the query instruction limit.
+const ENTRY_BUDGET: usize = 10_000;
+const BYTE_BUDGET: u64 = 32 * 1024 * 1024;
+
+// Matched lines are cut off after this many bytes to bound the response size.
+const MAX_LINE_LENGTH: usize = 1024;
+
+// A file is taken to be binary if its first bytes contain a NUL.
+const BINARY_PROBE_SIZE: usize = 8 * 1024;
+
+// What a result costs of the response size on top of its strings, roughly
+// the encoding of its numbers and length prefixes.
+const ENTRY_OVERHEAD: u64 = 64;
+const MATCH_OVERHEAD: u64 = 16;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FindResult {
+    pub entries: Vec<WalkEntry>,
+    pub next: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GrepMatch {
+    pub path: String,
+    pub line_number: u64,
+    pub line: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GrepResult {
+    pub matches: Vec<GrepMatch>,
+    pub next: Option<String>,
+}
+
+// Where `grep` stopped inside a file: the byte offset of the next line to
+// scan and how many lines came before it.
+type Position = (u64, u64);
+
+// The matches `grep` found so far and how many bytes of the response are
+// left for more.
+struct Found {
+    matches: Vec<GrepMatch>,
+    room: u64,
+}
+
+fn entry_cost(entry: &WalkEntry) -> u64 {
+    let info = &entry.info;
+    (entry.path.len()
+        + info.name.len()
+        + info.long_name.as_ref().map_or(0, String::len)
+        + info.short_name.len()) as u64
+        + ENTRY_OVERHEAD
+}
+
+// Cursors are the path of the last visited entry, prefixed with the position
+// `grep` reached in it.
+fn encode_cursor(components: &[String], (offset, line): Position) -> String {
+    format!("{}:{}:{}", offset, line, path::join(components))
+}
+
+fn decode_cursor(cursor: &str, root: &[String]) -> FsResult<(Vec<String>, Position)> {
+    let invalid = || FsError::InvalidArgument(format!("Invalid cursor: {}", cursor));
+    let mut parts = cursor.splitn(3, ':');
+    let mut number = || -> FsResult<u64> {
+        parts
+            .next()
+            .and_then(|number| number.parse().ok())
+            .ok_or_else(invalid)
+    };
+    let position = (number()?, number()?);
+    let components = path::normalize(parts.next().ok_or_else(invalid)?)?;
+    if !components.starts_with(root) {
+        return Err(invalid());
+    }
+    Ok((components, position))
+}
+
+/// Searches the tree below `root` for entries matching every given filter.
+/// A `glob` containing `/` is matched against the path relative to `root`,
+/// otherwise against the name; `*` stays within a directory and `**` spans
+/// any number. Size filters only match files, `modified_after` is in
+/// nanoseconds since the epoch. Results come in depth-first name order, at
+/// most `limit` at a time and no more than fit in the maximum response
+/// size; pass `next` back as `cursor` to continue.
+#[query]
+#[allow(clippy::too_many_arguments)]
+fn find(
+    root: String,
+    glob: Option<String>,
+    kind: Option<stat::FileKind>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    modified_after: Option<u64>,
+    cursor: Option<String>,
+    limit: u32,
+) -> FsResult<FindResult> {
+    let glob = glob.as_deref().map(Glob::new).transpose()?;
+    let limit = limit.max(1) as usize;
+
+    with_fs(|fs| {
+        let root = canonical(fs, &root)?;
+        let acls = Acls::load(fs)?;
+        acls.check(&root, Permission::Read)?;
+        let caller = ic_cdk::caller();
+        let after = cursor
+            .map(|cursor| decode_cursor(&cursor, &root))
+            .transpose()?
+            .map(|(after, _)| after);
+
+        let mut entries = vec![];
+        let mut budget = ENTRY_BUDGET;
+        let mut room = text::max_response_size(fs)?;
+        // An entry that doesn't fit any more is listed next time, so the
+        // cursor then points at the one visited before it.
+        let mut previous = after.clone().unwrap_or_else(|| root.clone());
+        let mut next = None;
+        let mut visit = |entry: &DirEntry, components: &[String], depth: u32| -> FsResult<Visit> {
+            budget -= 1;
+
+            let is_dir = entry.is_dir();
+            let entry_kind = if is_dir {
+                stat::FileKind::Directory
+            } else {
+                stat::FileKind::File
+            };
+            let matches = acls.allows(components, caller, Permission::Read)
+                && kind.as_ref().map_or(true, |kind| *kind == entry_kind)
+                && min_size.map_or(true, |min| !is_dir && entry.len() >= min)
+                && max_size.map_or(true, |max| !is_dir && entry.len() <= max)
+                && modified_after
+                    .map_or(true, |after| timestamp::to_nanos(entry.modified()) > after)
+                && glob.as_ref().map_or(true, |glob| {
+                    if glob.has_separator() {
+                        glob.matches(&components[root.len()..].join("/"))
+                    } else {
+                        glob.matches(components.last().unwrap())
+                    }
+                });
+
+            if matches {
+                let found = WalkEntry {
+                    path: path::join(components),
+                    depth,
+                    info: stat::entry_info(entry),
+                };
+                let cost = entry_cost(&found);
+                if cost > room && !entries.is_empty() {
+                    next = Some(encode_cursor(&previous, (0, 0)));
+                    return Ok(Visit::Stop);
+                }
+                room = room.saturating_sub(cost);
+                entries.push(found);
+            }
+
+            previous = components.to_vec();
+            if budget == 0 || entries.len() >= limit {
+                Ok(Visit::Stop)
+            } else {
+                Ok(Visit::Continue)
+            }
+        };
+
+        let dir = open_dir_components(fs, &root)?;
+        let last = walk_after(&dir, &root, 1, after.as_deref(), &mut visit)?;
+        Ok(FindResult {
+            entries,
+            next: next.or_else(|| last.map(|last| encode_cursor(&last, (0, 0)))),
+        })
+    })
+}
+
+// Scans the lines of `entry` from `start` on. Returns the position reached
+// once `max_matches` is reached, the byte budget is spent or a match doesn't
+// fit in the response any more, or None at the end of the file. A match that
+// doesn't fit is scanned again next time.
+fn grep_file(
+    entry: &DirEntry,
+    components: &[String],
+    regex: &Regex,
+    start: Position,
+    max_matches: usize,
+    budget: &mut u64,
+    found: &mut Found,
+) -> FsResult<Option<Position>> {
+    let mut file = entry.to_file();
+    let mut probe = vec![];
+    if start.0 == 0 {
+        (&mut file)
+            .take(BINARY_PROBE_SIZE as u64)
+            .read_to_end(&mut probe)?;
+        if probe.contains(&0) {
+            return Ok(None);
+        }
+    } else {
+        file.seek(SeekFrom::Start(start.0))?;
+    }
+
+    let mut reader = BufReader::new(probe.as_slice().chain(file));
+    let mut buf = vec![];
+    let (mut offset, mut line_number) = start;
+    loop {
+        buf.clear();
+        let read = reader.read_until(b'\n', &mut buf)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        offset += read as u64;
+        line_number += 1;
+        *budget = budget.saturating_sub(read as u64);
+
+        let line = String::from_utf8_lossy(&buf);
+        let line = line.trim_end_matches(|c| c == '\n' || c == '\r');
+        if regex.is_match(line) {
+            let mut end = line.len().min(MAX_LINE_LENGTH);
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            let found_match = GrepMatch {
+                path: path::join(components),
+                line_number,
+                line: line[..end].to_string(),
+            };
+            let cost = (found_match.path.len() + found_match.line.len()) as u64 + MATCH_OVERHEAD;
+            if cost > found.room && !found.matches.is_empty() {
+                return Ok(Some((offset - read as u64, line_number - 1)));
+            }
+            found.room = found.room.saturating_sub(cost);
+            found.matches.push(found_match);
+        }
+        if found.matches.len() >= max_matches || *budget == 0 {
+            return Ok(Some((offset, line_number)));
+        }
+    }
+}
+
+/// Searches the text files below `root` for lines matching the regular
+/// expression `pattern`. Binary files are skipped and lines are decoded
+/// lossily. Returns at most `max_matches` hits, and no more than fit in the
+/// maximum response size; pass `next` back as `cursor` to continue after
+/// them.
+#[query]
+fn grep(
+    root: String,
+    pattern: String,
+    max_matches: u32,
+    cursor: Option<String>,
+) -> FsResult<GrepResult> {
+    let regex = Regex::new(&pattern)
+        .map_err(|error| FsError::InvalidArgument(format!("Invalid pattern: {}", error)))?;
+    let max_matches = max_matches.max(1) as usize;
+
+    with_fs(|fs| {
+        let root = canonical(fs, &root)?;
+        let acls = Acls::load(fs)?;
+        acls.check(&root, Permission::Read)?;
+        let caller = ic_cdk::caller();
+        let mut found = Found {
+            matches: vec![],
+            room: text::max_response_size(fs)?,
+        };
+        let mut budget = BYTE_BUDGET;
+        let mut entry_budget = ENTRY_BUDGET;
+
+        // A file cut off in the middle is finished before the walk goes on.
+        let after = match cursor {
+            Some(cursor) => {
+                let (after, position) = decode_cursor(&cursor, &root)?;
+                if position.0 > 0 && acls.allows(&after, caller, Permission::Read) {
+                    let (name, parent) = after.split_last().ok_or_else(|| {
+                        FsError::InvalidArgument(format!("Invalid cursor: {}", cursor))
+                    })?;
+                    let parent = open_dir_components(fs, parent)?;
+                    if let Some(entry) = find_entry(&parent, name)?.filter(|entry| !entry.is_dir())
+                    {
+                        let scanned = grep_file(
+                            &entry,
+                            &after,
+                            &regex,
+                            position,
+                            max_matches,
+                            &mut budget,
+                            &mut found,
+                        )?;
+                        if let Some(scanned) = scanned {
+                            return Ok(GrepResult {
+                                matches: found.matches,
+                                next: Some(encode_cursor(&after, scanned)),
+                            });
+                        }
+                    }
+                }
+                Some(after)
+            }
+            None => None,
+        };
+
+        let mut next = None;
+        // A file whose first match doesn't fit is scanned again from the
+        // start, which takes a cursor pointing at the entry visited before.
+        let mut previous = after.clone().unwrap_or_else(|| root.clone());
+        let mut visit = |entry: &DirEntry, components: &[String], _depth: u32| -> FsResult<Visit> {
+            entry_budget -= 1;
+            if !entry.is_dir() && acls.allows(components, caller, Permission::Read) {
+                let scanned = grep_file(
+                    entry,
+                    components,
+                    &regex,
+                    (0, 0),
+                    max_matches,
+                    &mut budget,
+                    &mut found,
+                )?;
+                if let Some(scanned) = scanned {
+                    next = Some(if scanned.0 == 0 {
+                        encode_cursor(&previous, (0, 0))
+                    } else {
+                        encode_cursor(components, scanned)
+                    });
+                    return Ok(Visit::Stop);
+                }
+            }
+            previous = components.to_vec();
+
+            if budget == 0 || entry_budget == 0 {
+                Ok(Visit::Stop)
+            } else {
+                Ok(Visit::Continue)
+            }
+        };
+
+        let dir = open_dir_components(fs, &root)?;
+        let last = walk_after(&dir, &root, 1, after.as_deref(), &mut visit)?;
+        let next = next.or_else(|| last.map(|last| encode_cursor(&last, (0, 0))));
+        Ok(GrepResult {
+            matches: found.matches,
+            next,
+        })
+    })
+}
@@ -0,0 +1,184 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::stable::{stable64_read, stable64_size, stable64_write};
+use ic_cdk_macros::{query, update};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+
+use super::{
+    acl,
+    fat::BootSector,
+    upload::{self, UploadTarget},
+    volume::{ensure_stable_pages, WASM_PAGE_SIZE},
+    with_unmounted, FsError, FsResult,
+};
+
+// Small enough to stay well below the message size limit of a query reply.
+const CHUNK_SIZE: u64 = 1024 * 1024;
+
+// Chunks `volume_digest` hashes in one call, so large images don't run into
+// the query instruction limit.
+const DIGEST_CHUNKS: u64 = 32;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct VolumeChunk {
+    pub index: u64,
+    pub chunk_count: u64,
+    pub image_size: u64,
+    pub data: ByteBuf,
+    pub sha256: ByteBuf,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct VolumeDigest {
+    pub image_size: u64,
+    pub chunk_size: u64,
+    pub chunk_count: u64,
+    pub first: u64,
+    pub chunk_sha256: Vec<ByteBuf>,
+    // Hash of the whole image, when this page covers all of it.
+    pub sha256: Option<ByteBuf>,
+    pub next: Option<u64>,
+}
+
+/// Size of the FAT image: the sectors the boot sector claims, as far as
+/// they are actually backed by stable memory.
+fn image_size() -> FsResult<u64> {
+    let boot_sector = BootSector::read()?;
+    let size = u64::from(boot_sector.total_sectors) * u64::from(boot_sector.bytes_per_sector);
+    Ok(size.min(stable64_size() * WASM_PAGE_SIZE))
+}
+
+fn chunk_count(image_size: u64) -> u64 {
+    (image_size + CHUNK_SIZE - 1) / CHUNK_SIZE
+}
+
+fn read_chunk(image_size: u64, index: u64) -> Vec<u8> {
+    let offset = index * CHUNK_SIZE;
+    let mut data = vec![0; (image_size - offset).min(CHUNK_SIZE) as usize];
+    stable64_read(offset, &mut data);
+    data
+}
+
+/// Returns chunk `index` of the raw FAT image. Concatenating all
+/// `chunk_count` chunks gives an image that mtools and friends can open.
+#[query]
+fn export_volume(index: u64) -> FsResult<VolumeChunk> {
+    acl::check_volume()?;
+    let image_size = image_size()?;
+    let chunk_count = chunk_count(image_size);
+    if index >= chunk_count {
+        return Err(FsError::InvalidArgument(format!(
+            "Chunk {} out of range, the image has {} chunks",
+            index, chunk_count
+        )));
+    }
+
+    let data = read_chunk(image_size, index);
+    let sha256 = Sha256::digest(&data).to_vec();
+    Ok(VolumeChunk {
+        index,
+        chunk_count,
+        image_size,
+        data: ByteBuf::from(data),
+        sha256: ByteBuf::from(sha256),
+    })
+}
+
+/// Hashes the image chunk by chunk from chunk `first` on, so an export can
+/// be checked against the state it was taken from. Large images take several
+/// calls; pass `next` back as `first` to continue. The hash of the whole
+/// image is only included when one call covers all of it.
+#[query]
+fn volume_digest(first: u64) -> FsResult<VolumeDigest> {
+    acl::check_volume()?;
+    let image_size = image_size()?;
+    let chunk_count = chunk_count(image_size);
+    if first > chunk_count {
+        return Err(FsError::InvalidArgument(format!(
+            "Chunk {} out of range, the image has {} chunks",
+            first, chunk_count
+        )));
+    }
+
+    let end = chunk_count.min(first + DIGEST_CHUNKS);
+    let mut image_hasher = Sha256::new();
+    let chunk_sha256 = (first..end)
+        .map(|index| {
+            let data = read_chunk(image_size, index);
+            image_hasher.update(&data);
+            ByteBuf::from(Sha256::digest(&data).to_vec())
+        })
+        .collect();
+
+    let whole = first == 0 && end == chunk_count;
+    Ok(VolumeDigest {
+        image_size,
+        chunk_size: CHUNK_SIZE,
+        chunk_count,
+        first,
+        chunk_sha256,
+        sha256: whole.then(|| ByteBuf::from(image_hasher.finalize().to_vec())),
+        next: (end < chunk_count).then(|| end),
+    })
+}
+
+/// Starts staging a volume image. Chunks go through `put_chunk` like any
+/// other upload, `import_volume` then replaces the volume with the image.
+/// Only volume admins may stage one.
+#[update]
+fn begin_volume_import() -> FsResult<u64> {
+    acl::check_volume()?;
+    upload::begin(UploadTarget::Volume)
+}
+
+/// Replaces the whole volume with the image staged in upload `id` and
+/// returns the image size. The image must carry a valid boot sector and
+/// mount cleanly, otherwise the current volume is left alone.
+#[update]
+fn import_volume(id: u64, sha256: Option<ByteBuf>) -> FsResult<u64> {
+    acl::check_volume()?;
+    upload::with_session(id, |session| {
+        if !matches!(session.target, UploadTarget::Volume) {
+            return Err(FsError::InvalidArgument(format!(
+                "Upload {} is not a volume image",
+                id
+            )));
+        }
+        upload::verify_checksum(&session.data, sha256)?;
+
+        let invalid =
+            |reason: String| FsError::InvalidArgument(format!("Invalid volume image: {}", reason));
+        let boot_sector =
+            BootSector::parse(&session.data).map_err(|_| invalid("bad boot sector".to_string()))?;
+        let image_size =
+            u64::from(boot_sector.total_sectors) * u64::from(boot_sector.bytes_per_sector);
+        if image_size > session.data.len() as u64 {
+            return Err(invalid(format!(
+                "boot sector claims {} bytes, only {} were uploaded",
+                image_size,
+                session.data.len()
+            )));
+        }
+
+        // Mount the staged image in place first, so a broken image never
+        // replaces a good volume. It is unmounted again right away.
+        drop(
+            fatfs::FileSystem::new(
+                std::io::Cursor::new(&mut session.data[..]),
+                fatfs::FsOptions::new(),
+            )
+            .map_err(|error| invalid(error.to_string()))?,
+        );
+
+        with_unmounted(|| {
+            ensure_stable_pages((image_size + WASM_PAGE_SIZE - 1) / WASM_PAGE_SIZE)?;
+            stable64_write(0, &session.data[..image_size as usize]);
+            Ok(())
+        })?;
+        Ok(image_size)
+    })
+    .map(|image_size| {
+        upload::close(id);
+        image_size
+    })
+}
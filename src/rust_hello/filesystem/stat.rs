@@ -0,0 +1,174 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::query;
+
+use super::{
+    acl::{self, Permission},
+    canonical, find_entry, open_dir_components, path, timestamp, with_fs, DirEntry, FsError,
+    FsResult,
+};
+
+// Upper bound for `limit` in `ls_page`, to stay within the response size.
+const MAX_PAGE_SIZE: u32 = 1_000;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum FileKind {
+    File,
+    Directory,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FileAttributes {
+    pub read_only: bool,
+    pub hidden: bool,
+    pub system: bool,
+    pub archive: bool,
+}
+
+/// Metadata of a single directory entry. Timestamps are nanoseconds since the
+/// Unix epoch; FAT only keeps the date for `accessed`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FileInfo {
+    pub name: String,
+    pub long_name: Option<String>,
+    pub short_name: String,
+    pub kind: FileKind,
+    pub size: u64,
+    pub created: u64,
+    pub modified: u64,
+    pub accessed: u64,
+    pub attributes: FileAttributes,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LsPage {
+    pub entries: Vec<FileInfo>,
+    pub next: Option<String>,
+}
+
+pub fn entry_info(entry: &DirEntry) -> FileInfo {
+    let attributes = entry.attributes();
+
+    FileInfo {
+        name: entry.file_name(),
+        long_name: entry
+            .long_file_name_as_ucs2_units()
+            .map(String::from_utf16_lossy),
+        short_name: entry.short_file_name(),
+        kind: if entry.is_dir() {
+            FileKind::Directory
+        } else {
+            FileKind::File
+        },
+        size: entry.len(),
+        created: timestamp::to_nanos(entry.created()),
+        modified: timestamp::to_nanos(entry.modified()),
+        accessed: timestamp::date_to_nanos(entry.accessed()),
+        attributes: FileAttributes {
+            read_only: attributes.contains(fatfs::FileAttributes::READ_ONLY),
+            hidden: attributes.contains(fatfs::FileAttributes::HIDDEN),
+            system: attributes.contains(fatfs::FileAttributes::SYSTEM),
+            archive: attributes.contains(fatfs::FileAttributes::ARCHIVE),
+        },
+    }
+}
+
+// The root directory has no entry of its own, so its metadata is synthesized.
+fn root_info() -> FileInfo {
+    FileInfo {
+        name: "/".to_string(),
+        long_name: None,
+        short_name: "/".to_string(),
+        kind: FileKind::Directory,
+        size: 0,
+        created: 0,
+        modified: 0,
+        accessed: 0,
+        attributes: FileAttributes {
+            read_only: false,
+            hidden: false,
+            system: false,
+            archive: false,
+        },
+    }
+}
+
+fn is_dot_entry(entry: &DirEntry) -> bool {
+    matches!(entry.file_name().as_str(), "." | "..")
+}
+
+#[query]
+fn stat(path: String) -> FsResult<FileInfo> {
+    with_fs(|fs| {
+        acl::check_path(fs, &path, Permission::Read)?;
+        let components = canonical(fs, &path)?;
+        let (name, dir_path) = match components.split_last() {
+            Some(split) => split,
+            None => return Ok(root_info()),
+        };
+
+        let dir = open_dir_components(fs, dir_path)?;
+        let entry = find_entry(&dir, name)?.ok_or(FsError::NotFound)?;
+        Ok(entry_info(&entry))
+    })
+}
+
+/// Lists `path` with metadata for every entry, sorted by name. The `.` and
+/// `..` entries of subdirectories are left out.
+#[query]
+fn ls_long(path: String) -> FsResult<Vec<FileInfo>> {
+    with_fs(|fs| {
+        acl::check_path(fs, &path, Permission::Read)?;
+        let components = canonical(fs, &path)?;
+        let dir = open_dir_components(fs, &components)?;
+        let mut entries = vec![];
+        for entry in dir.iter() {
+            let entry = entry?;
+            if !is_dot_entry(&entry) && !path::is_system(&components, &entry.file_name()) {
+                entries.push(entry_info(&entry));
+            }
+        }
+
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    })
+}
+
+/// Lists up to `limit` entries of `path` with metadata, in on-disk order
+/// rather than sorted, so each page only reads as far as it needs to. Pass
+/// `next` back as `cursor` for the following page; cursors stay valid as
+/// long as the directory isn't modified.
+#[query]
+fn ls_page(path: String, cursor: Option<String>, limit: u32) -> FsResult<LsPage> {
+    // The cursor is the number of raw directory entries already passed.
+    let skip = match cursor {
+        Some(cursor) => cursor
+            .parse()
+            .map_err(|_| FsError::InvalidArgument(format!("Invalid cursor: {}", cursor)))?,
+        None => 0,
+    };
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+
+    with_fs(|fs| {
+        acl::check_path(fs, &path, Permission::Read)?;
+        let components = canonical(fs, &path)?;
+        let dir = open_dir_components(fs, &components)?;
+        let mut page = LsPage {
+            entries: vec![],
+            next: None,
+        };
+        let mut position: u64 = skip;
+        for entry in dir.iter().skip(skip as usize) {
+            let entry = entry?;
+            if page.entries.len() == limit {
+                page.next = Some(position.to_string());
+                break;
+            }
+
+            position += 1;
+            if !is_dot_entry(&entry) && !path::is_system(&components, &entry.file_name()) {
+                page.entries.push(entry_info(&entry));
+            }
+        }
+        Ok(page)
+    })
+}
@@ -0,0 +1,105 @@
+use std::io::Read;
+
+use candid::{CandidType, Deserialize};
+use fatfs::Write;
+
+use super::{find_entry, path::SYSTEM_DIR, Dir, FileSystem, FsError, FsResult};
+
+// Metadata files are candid-encoded values in the system directory, so they
+// live on the volume and survive upgrades, snapshots and imports with it.
+
+fn system_dir<'a>(fs: &'a FileSystem) -> FsResult<Option<Dir<'a>>> {
+    match find_entry(&fs.root_dir(), SYSTEM_DIR)? {
+        Some(entry) if entry.is_dir() => Ok(Some(entry.to_dir())),
+        Some(_) => Err(FsError::Io(format!("{} is not a directory", SYSTEM_DIR))),
+        None => Ok(None),
+    }
+}
+
+/// Reads the metadata file `name`, if it exists.
+pub fn load<T>(fs: &FileSystem, name: &str) -> FsResult<Option<T>>
+where
+    T: CandidType + for<'de> Deserialize<'de>,
+{
+    match system_dir(fs)? {
+        Some(dir) => load_from(&dir, name),
+        None => Ok(None),
+    }
+}
+
+/// Reads the metadata file `name` in `dir`, a directory below the system
+/// directory, if it exists.
+pub fn load_from<T>(dir: &Dir, name: &str) -> FsResult<Option<T>>
+where
+    T: CandidType + for<'de> Deserialize<'de>,
+{
+    let mut file = match find_entry(dir, name)? {
+        Some(entry) => entry.to_file(),
+        None => return Ok(None),
+    };
+
+    let mut bytes = vec![];
+    file.read_to_end(&mut bytes)?;
+    let value = candid::decode_one(&bytes)
+        .map_err(|error| FsError::Io(format!("Corrupt metadata file {}: {}", name, error)))?;
+    Ok(Some(value))
+}
+
+/// Replaces the metadata file `name` with `value`. Once the old contents are
+/// truncated, a failure traps rather than returning, which rolls back the
+/// call instead of leaving a half-written file behind.
+pub fn store<T: CandidType>(fs: &FileSystem, name: &str, value: &T) -> FsResult<()> {
+    let root = fs.root_dir();
+    let dir = match system_dir(fs)? {
+        Some(dir) => dir,
+        None => root.create_dir(SYSTEM_DIR)?,
+    };
+    store_in(&dir, name, value)
+}
+
+/// Like `store`, for the metadata file `name` in `dir`.
+pub fn store_in<T: CandidType>(dir: &Dir, name: &str, value: &T) -> FsResult<()> {
+    let bytes = candid::encode_one(value).map_err(|error| FsError::Io(error.to_string()))?;
+
+    let mut file = dir.create_file(name)?;
+    let result = file
+        .truncate()
+        .and_then(|()| file.write_all(&bytes))
+        .and_then(|()| file.flush());
+    if let Err(error) = result {
+        ic_cdk::trap(&format!(
+            "Failed to write metadata file {}: {:?}",
+            name,
+            FsError::from(error)
+        ));
+    }
+    Ok(())
+}
+
+/// Opens the directory `names` below the system directory, creating it and
+/// any missing parents.
+pub fn dir<'a>(fs: &'a FileSystem, names: &[&str]) -> FsResult<Dir<'a>> {
+    let root = fs.root_dir();
+    let mut dir = match system_dir(fs)? {
+        Some(dir) => dir,
+        None => root.create_dir(SYSTEM_DIR)?,
+    };
+    for name in names {
+        dir = match find_entry(&dir, name)? {
+            Some(entry) if entry.is_dir() => entry.to_dir(),
+            Some(_) => return Err(FsError::Io(format!("{} is not a directory", name))),
+            None => dir.create_dir(name)?,
+        };
+    }
+    Ok(dir)
+}
+
+/// Deletes the metadata file `name`, if it exists.
+pub fn remove(fs: &FileSystem, name: &str) -> FsResult<()> {
+    if let Some(dir) = system_dir(fs)? {
+        if find_entry(&dir, name)?.is_some() {
+            dir.remove(name)?;
+        }
+    }
+    Ok(())
+}
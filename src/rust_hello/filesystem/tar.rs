@@ -0,0 +1,829 @@
+use std::{cell::RefCell, collections::BTreeMap, io::Read};
+
+use candid::{CandidType, Deserialize, Principal};
+use fatfs::{Seek, SeekFrom, Write};
+use ic_cdk_macros::update;
+use serde_bytes::ByteBuf;
+
+use super::{
+    acl::{self, Permission},
+    canonical, canonicalize, create_file,
+    download::{self, DownloadInfo, MAX_DOWNLOAD_SIZE, MAX_HELD_BYTES},
+    find_entry, home, open_dir_components, open_file, path, record, timestamp,
+    upload::{self, UploadTarget},
+    with_fs, zip_archive, Change, Dir, FileSystem, FsError, FsResult,
+};
+
+// Work a single call may do before it returns and asks to be continued, so
+// big trees and archives don't run into the instruction limit.
+const ENTRY_BUDGET: u64 = 10_000;
+const BYTE_BUDGET: u64 = 32 * 1024 * 1024;
+
+// Exports a caller may have running at once.
+const MAX_EXPORTS: usize = 4;
+
+// Exports not continued for this long are dropped when the next one starts.
+const EXPORT_TIMEOUT: u64 = 60 * 60 * 1_000_000_000;
+
+const BLOCK_SIZE: usize = 512;
+const EXTRACT_BUFFER_SIZE: usize = 64 * 1024;
+
+const REGULAR: u8 = b'0';
+const DIRECTORY: u8 = b'5';
+const PAX_HEADER: u8 = b'x';
+const PAX_GLOBAL_HEADER: u8 = b'g';
+const GNU_LONG_NAME: u8 = b'L';
+const CONTIGUOUS: u8 = b'7';
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SkippedEntry {
+    pub path: String,
+    pub reason: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TarExport {
+    pub job: u64,
+    pub entries: u64,
+    pub complete: bool,
+    pub download: Option<DownloadInfo>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct TarImportReport {
+    pub files: u64,
+    pub directories: u64,
+    pub skipped: Vec<SkippedEntry>,
+    pub complete: bool,
+}
+
+struct ExportJob {
+    owner: Principal,
+    // When a call last continued the export, in nanoseconds since the epoch.
+    last_used: u64,
+    out: Vec<u8>,
+    // Components of the exported directory, member names are relative to it.
+    root_len: usize,
+    // Entries still to visit, the next one last.
+    pending: Vec<Vec<String>>,
+    // A file whose contents are partly written, its size as given in the
+    // header and how far.
+    current: Option<(Vec<String>, u64, u64)>,
+    entries: u64,
+}
+
+thread_local! {
+    static EXPORTS: RefCell<BTreeMap<u64, ExportJob>> = RefCell::default();
+    static NEXT_EXPORT_ID: RefCell<u64> = RefCell::new(0);
+}
+
+impl TarImportReport {
+    fn skip(&mut self, path: &str, reason: String) {
+        self.skipped.push(SkippedEntry {
+            path: path.to_string(),
+            reason,
+        });
+    }
+}
+
+fn padding(len: usize) -> usize {
+    (BLOCK_SIZE - len % BLOCK_SIZE) % BLOCK_SIZE
+}
+
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+}
+
+// Splits a name into the ustar prefix and name fields, if it fits them.
+fn split_name(name: &str) -> Option<(&str, &str)> {
+    if name.len() <= 100 {
+        return Some(("", name));
+    }
+
+    name.char_indices()
+        .filter(|&(i, c)| c == '/' && i <= 155 && name.len() - i - 1 <= 100)
+        .map(|(i, _)| (&name[..i], &name[i + 1..]))
+        .find(|(_, name)| !name.is_empty())
+}
+
+// Sum of the header bytes with the checksum field itself counted as spaces.
+fn checksum(header: &[u8]) -> u64 {
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, &byte)| {
+            if (148..156).contains(&i) {
+                32
+            } else {
+                u64::from(byte)
+            }
+        })
+        .sum()
+}
+
+fn write_block(out: &mut Vec<u8>, name: &str, kind: u8, size: u64, mtime: u64) {
+    // Names that don't fit are carried by a pax header, the field just gets a truncated copy.
+    let (prefix, name) = split_name(name).unwrap_or_else(|| {
+        let mut end = 100;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        ("", &name[..end])
+    });
+
+    let mut header = [0; BLOCK_SIZE];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(
+        &mut header[100..108],
+        if kind == DIRECTORY { 0o755 } else { 0o644 },
+    );
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], size);
+    write_octal(&mut header[136..148], mtime);
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    write_octal(&mut header[148..155], checksum(&header));
+    header[154] = 0;
+    header[155] = b' ';
+
+    out.extend_from_slice(&header);
+}
+
+// Writes the header for one member. Names that don't fit the ustar fields
+// get a pax extended header carrying the full path in front.
+fn write_header(out: &mut Vec<u8>, name: &str, kind: u8, size: u64, mtime: u64) {
+    if split_name(name).is_none() {
+        // The record length counts its own digits.
+        let record = format!(" path={}\n", name);
+        let mut len = record.len() + 1;
+        while len.to_string().len() + record.len() != len {
+            len = len.to_string().len() + record.len();
+        }
+        let record = format!("{}{}", len, record);
+
+        write_block(
+            out,
+            "././@PaxHeader",
+            PAX_HEADER,
+            record.len() as u64,
+            mtime,
+        );
+        out.extend_from_slice(record.as_bytes());
+        out.resize(out.len() + padding(record.len()), 0);
+    }
+    write_block(out, name, kind, size, mtime);
+}
+
+/// Returns the entries of the directory at `dir_path`, other than the
+/// system directory, sorted by name.
+pub fn sorted_children(dir: &Dir, dir_path: &[String]) -> FsResult<Vec<Vec<String>>> {
+    let mut children = vec![];
+    for entry in dir.iter() {
+        let name = entry?.file_name();
+        if name != "." && name != ".." && !path::is_system(dir_path, &name) {
+            let mut components = dir_path.to_vec();
+            components.push(name);
+            children.push(components);
+        }
+    }
+
+    children.sort();
+    Ok(children)
+}
+
+impl ExportJob {
+    fn member_name(&self, components: &[String]) -> String {
+        components[self.root_len..].join("/")
+    }
+
+    // Writes more of the current file. Returns false when the budget ran out first.
+    fn copy_current(&mut self, fs: &FileSystem, budget: &mut u64) -> FsResult<bool> {
+        let (components, size, offset) = match self.current.take() {
+            Some(current) => current,
+            None => return Ok(true),
+        };
+        let (name, parent) = components.split_last().ok_or(FsError::InvalidPath)?;
+        let mut file = open_file(&open_dir_components(fs, parent)?, name)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let len = (size - offset).min(*budget);
+        let start = self.out.len();
+        (&mut file).take(len).read_to_end(&mut self.out)?;
+        // Keep the archive consistent with the header even if the file shrank.
+        self.out.resize(start + len as usize, 0);
+        *budget = budget.saturating_sub(len);
+
+        let offset = offset + len;
+        if offset < size {
+            self.current = Some((components, size, offset));
+            return Ok(false);
+        }
+        self.out.resize(self.out.len() + padding(size as usize), 0);
+        Ok(true)
+    }
+
+    // Adds members until everything is written or the budget runs out, with
+    // the archive staying within `limit` bytes. Returns whether it is complete.
+    fn run(&mut self, fs: &FileSystem, limit: u64) -> FsResult<bool> {
+        let mut budget = BYTE_BUDGET;
+        let mut entry_budget = ENTRY_BUDGET;
+        loop {
+            if !self.copy_current(fs, &mut budget)? {
+                return Ok(false);
+            }
+            if entry_budget == 0 || budget == 0 {
+                return Ok(self.pending.is_empty());
+            }
+
+            let components = match self.pending.pop() {
+                Some(components) => components,
+                None => return Ok(true),
+            };
+            entry_budget -= 1;
+
+            let (name, parent) = components.split_last().ok_or(FsError::InvalidPath)?;
+            // Entries removed since the export started are left out.
+            let entry = match find_entry(&open_dir_components(fs, parent)?, name)? {
+                Some(entry) => entry,
+                None => continue,
+            };
+
+            let member_name = self.member_name(&components);
+            let mtime = timestamp::to_unix_secs(entry.modified());
+            if entry.is_dir() {
+                write_header(
+                    &mut self.out,
+                    &format!("{}/", member_name),
+                    DIRECTORY,
+                    0,
+                    mtime,
+                );
+                let mut children = sorted_children(&entry.to_dir(), &components)?;
+                children.reverse();
+                self.pending.extend(children);
+            } else {
+                // Headers take at most three blocks, a pax header included.
+                let size = entry.len();
+                let projected = self.out.len() as u64 + 3 * BLOCK_SIZE as u64 + size;
+                if projected > limit {
+                    return Err(FsError::NoSpace);
+                }
+                write_header(&mut self.out, &member_name, REGULAR, size, mtime);
+                self.current = Some((components, size, 0));
+            }
+            self.entries += 1;
+
+            if self.out.len() as u64 > limit {
+                return Err(FsError::NoSpace);
+            }
+        }
+    }
+}
+
+/// Bytes the tar exports still being built hold.
+pub fn exporting_bytes() -> u64 {
+    EXPORTS.with(|exports| {
+        exports
+            .borrow()
+            .values()
+            .map(|job| job.out.len() as u64)
+            .sum()
+    })
+}
+
+// Runs export `id` for another slice and hands out the archive once it is
+// done. Together with the other exports and the open downloads it has to
+// stay within `MAX_HELD_BYTES`.
+fn continue_export(id: u64) -> FsResult<TarExport> {
+    EXPORTS.with(|exports| {
+        let mut exports = exports.borrow_mut();
+        let others = exports
+            .iter()
+            .filter(|(other, _)| **other != id)
+            .map(|(_, job)| job.out.len() as u64)
+            .sum::<u64>()
+            + zip_archive::exporting_bytes();
+        let limit = MAX_HELD_BYTES
+            .saturating_sub(others + download::held_bytes())
+            .min(MAX_DOWNLOAD_SIZE);
+        let job = exports
+            .get_mut(&id)
+            .filter(|job| job.owner == ic_cdk::caller())
+            .ok_or(FsError::NotFound)?;
+        job.last_used = ic_cdk::api::time();
+
+        let complete = match with_fs(|fs| job.run(fs, limit)) {
+            Ok(complete) => complete,
+            Err(error) => {
+                exports.remove(&id);
+                return Err(error);
+            }
+        };
+        let entries = job.entries;
+
+        let download = if complete {
+            let mut archive = exports.remove(&id).unwrap().out;
+            archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+            Some(download::begin(archive, others)?)
+        } else {
+            None
+        };
+
+        Ok(TarExport {
+            job: id,
+            entries,
+            complete,
+            download,
+        })
+    })
+}
+
+/// Starts packing `path` into a ustar archive. Member names are relative to
+/// `path`; a single file is archived under its own name. Large trees take
+/// several calls: until `complete` is set, continue with `continue_tar_export`.
+/// The finished archive is handed out as a download. Exports not continued
+/// for an hour are dropped.
+#[update]
+fn export_tar(path: String) -> FsResult<TarExport> {
+    let components = with_fs(|fs| {
+        let components = canonical(fs, &path)?;
+        acl::check_tree(fs, &components, Permission::Read)?;
+        Ok(components)
+    })?;
+    let (root_len, pending) = with_fs(|fs| match components.split_last() {
+        Some((name, parent)) => {
+            let entry =
+                find_entry(&open_dir_components(fs, parent)?, name)?.ok_or(FsError::NotFound)?;
+            if entry.is_dir() {
+                let mut children = sorted_children(&entry.to_dir(), &components)?;
+                children.reverse();
+                Ok((components.len(), children))
+            } else {
+                Ok((parent.len(), vec![components.clone()]))
+            }
+        }
+        None => {
+            let mut children = sorted_children(&fs.root_dir(), &[])?;
+            children.reverse();
+            Ok((0, children))
+        }
+    })?;
+
+    let owner = ic_cdk::caller();
+    let now = ic_cdk::api::time();
+    EXPORTS.with(|exports| {
+        let mut exports = exports.borrow_mut();
+        exports.retain(|_, job| job.last_used.saturating_add(EXPORT_TIMEOUT) > now);
+        if exports.values().filter(|job| job.owner == owner).count() >= MAX_EXPORTS {
+            return Err(FsError::InvalidArgument(format!(
+                "At most {} tar exports can run at once",
+                MAX_EXPORTS
+            )));
+        }
+        Ok(())
+    })?;
+
+    let id = NEXT_EXPORT_ID.with(|next| {
+        let mut next = next.borrow_mut();
+        *next += 1;
+        *next
+    });
+    EXPORTS.with(|exports| {
+        exports.borrow_mut().insert(
+            id,
+            ExportJob {
+                owner,
+                last_used: now,
+                out: vec![],
+                root_len,
+                pending,
+                current: None,
+                entries: 0,
+            },
+        )
+    });
+
+    continue_export(id)
+}
+
+#[update]
+fn continue_tar_export(job: u64) -> FsResult<TarExport> {
+    continue_export(job)
+}
+
+fn invalid_archive(offset: usize) -> FsError {
+    FsError::InvalidArgument(format!("Invalid tar header at offset {}", offset))
+}
+
+fn parse_number(field: &[u8]) -> Option<u64> {
+    // GNU tar stores values too large for octal as big-endian base-256.
+    if field[0] & 0x80 != 0 {
+        return field[1..]
+            .iter()
+            .try_fold(u64::from(field[0] & 0x7F), |value, &byte| {
+                value.checked_mul(256).map(|value| value + u64::from(byte))
+            });
+    }
+
+    let digits = std::str::from_utf8(field).ok()?;
+    let digits = digits.trim_matches(|c| c == '\0' || c == ' ');
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+fn parse_string(field: &[u8]) -> String {
+    let len = field
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..len]).into_owned()
+}
+
+fn pax_path(records: &[u8]) -> Option<String> {
+    let records = String::from_utf8_lossy(records);
+    records
+        .lines()
+        .filter_map(|record| record.split_once(' ')?.1.strip_prefix("path="))
+        .last()
+        .map(str::to_string)
+}
+
+pub fn open_or_create_dir<'a>(dir: &Dir<'a>, name: &str) -> FsResult<Dir<'a>> {
+    match find_entry(dir, name)? {
+        Some(entry) if entry.is_dir() => Ok(entry.to_dir()),
+        Some(_) => Err(FsError::NotADirectory),
+        None => Ok(dir.create_dir(name)?),
+    }
+}
+
+/// Creates the directory or file at `components` below `root`, along with
+/// any missing parents. Files get `contents` and `mtime`.
+pub fn extract(
+    root: &Dir,
+    components: &[String],
+    is_dir: bool,
+    mut contents: impl Read,
+    mtime: fatfs::DateTime,
+) -> FsResult<()> {
+    let (name, parents) = components.split_last().ok_or(FsError::InvalidPath)?;
+    let dir = parents
+        .iter()
+        .try_fold(root.clone(), |dir, name| open_or_create_dir(&dir, name))?;
+
+    if is_dir {
+        open_or_create_dir(&dir, name)?;
+    } else {
+        let mut file = create_file(&dir, name)?;
+        file.truncate()?;
+
+        let mut buf = vec![0; EXTRACT_BUFFER_SIZE];
+        loop {
+            let read = contents.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            file.write_all(&buf[..read])?;
+        }
+        file.set_modified(mtime);
+        file.flush()?;
+    }
+    Ok(())
+}
+
+/// Writes `data` at `offset` of the file at `components` below `root`, which
+/// is created along with any missing parents when `offset` is 0. The mtime is
+/// set with the last part.
+pub fn extract_part(
+    root: &Dir,
+    components: &[String],
+    offset: u64,
+    data: &[u8],
+    mtime: Option<fatfs::DateTime>,
+) -> FsResult<()> {
+    let (name, parents) = components.split_last().ok_or(FsError::InvalidPath)?;
+    let dir = parents
+        .iter()
+        .try_fold(root.clone(), |dir, name| open_or_create_dir(&dir, name))?;
+
+    let mut file = if offset == 0 {
+        let mut file = create_file(&dir, name)?;
+        file.truncate()?;
+        file
+    } else {
+        let mut file = open_file(&dir, name)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file
+    };
+    file.write_all(data)?;
+    if let Some(mtime) = mtime {
+        file.set_modified(mtime);
+    }
+    file.flush()?;
+    Ok(())
+}
+
+// Extracts members from the header at `next_header` on, skipping the first
+// `written` bytes of that member's data, until the archive or the budget is
+// exhausted. A member cut off by the budget is continued by the next call.
+fn unpack(
+    archive: &[u8],
+    next_header: &mut usize,
+    written: &mut u64,
+    fs: &FileSystem,
+    root: &Dir,
+    target: &[String],
+    changes: &mut Vec<Change>,
+) -> FsResult<TarImportReport> {
+    let mut report = TarImportReport::default();
+    let mut budget = BYTE_BUDGET;
+    let mut entry_budget = ENTRY_BUDGET;
+    let mut long_name = None;
+    let mut offset = *next_header;
+    loop {
+        // Calls only stop between members, or in the middle of one, so that
+        // the headers carrying a long name are read again on resume.
+        if long_name.is_none() {
+            *next_header = offset;
+            if budget == 0 || entry_budget == 0 {
+                return Ok(report);
+            }
+        }
+        if offset + BLOCK_SIZE > archive.len() {
+            break;
+        }
+        let header = &archive[offset..offset + BLOCK_SIZE];
+        if header.iter().all(|&byte| byte == 0) {
+            break;
+        }
+
+        let fields = (
+            parse_number(&header[148..156]),
+            parse_number(&header[124..136]),
+            parse_number(&header[136..148]),
+        );
+        let (size, mtime) = match fields {
+            (Some(expected), Some(size), Some(mtime)) if expected == checksum(header) => {
+                (size, mtime)
+            }
+            _ => return Err(invalid_archive(offset)),
+        };
+        let data_start = offset + BLOCK_SIZE;
+        let data_end = data_start
+            .checked_add(size as usize)
+            .filter(|&end| end <= archive.len())
+            .ok_or_else(|| invalid_archive(offset))?;
+        let data = &archive[data_start..data_end];
+        offset = data_end + padding(size as usize);
+
+        let kind = header[156];
+        match kind {
+            PAX_HEADER => {
+                long_name = pax_path(data).or(long_name);
+                continue;
+            }
+            GNU_LONG_NAME => {
+                long_name = Some(parse_string(data));
+                continue;
+            }
+            PAX_GLOBAL_HEADER => continue,
+            _ => {}
+        }
+        entry_budget -= 1;
+
+        let name = long_name.take().unwrap_or_else(|| {
+            let name = parse_string(&header[..100]);
+            let prefix = parse_string(&header[345..500]);
+            if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                format!("{}/{}", prefix, name)
+            } else {
+                name
+            }
+        });
+
+        let is_file = kind == REGULAR || kind == 0 || kind == CONTIGUOUS;
+        let is_dir = kind == DIRECTORY || (is_file && name.ends_with('/'));
+        if !is_file && !is_dir {
+            report.skip(&name, format!("Unsupported entry type '{}'", kind as char));
+            continue;
+        }
+
+        // Members always land below the target, `..` can't climb out of it.
+        let components = match path::normalize(&name) {
+            Ok(components) if components.is_empty() => continue,
+            Ok(components) => components,
+            Err(_) => {
+                report.skip(&name, "Invalid member path".to_string());
+                continue;
+            }
+        };
+
+        let mtime = timestamp::from_unix_secs(mtime);
+        let mut full_path = target.to_vec();
+        full_path.extend(components.iter().cloned());
+        let full_path = canonicalize(fs, full_path)?;
+        let result = if is_dir {
+            home::reserve_dir(fs, &full_path)
+                .and_then(|()| extract(root, &components, true, std::io::empty(), mtime))
+        } else {
+            let rest = &data[*written as usize..];
+            let len = rest.len().min(budget as usize);
+            let last = len == rest.len();
+            let result = home::reserve_file(fs, &full_path, *written + len as u64).and_then(|()| {
+                extract_part(
+                    root,
+                    &components,
+                    *written,
+                    &rest[..len],
+                    last.then(|| mtime),
+                )
+            });
+            budget -= len as u64;
+            if result.is_ok() && !last {
+                *written += len as u64;
+                changes.push(Change::Written(full_path));
+                return Ok(report);
+            }
+            *written = 0;
+            result
+        };
+
+        match result {
+            Ok(()) if is_dir => {
+                report.directories += 1;
+                changes.push(Change::Created(full_path));
+            }
+            Ok(()) => {
+                report.files += 1;
+                changes.push(Change::Written(full_path));
+            }
+            Err(
+                error @ (FsError::InvalidPath
+                | FsError::NotADirectory
+                | FsError::IsADirectory
+                | FsError::AlreadyExists),
+            ) => report.skip(&name, format!("{:?}", error)),
+            Err(error) => return Err(error),
+        }
+    }
+
+    report.complete = true;
+    Ok(report)
+}
+
+/// Starts staging a tar archive that `import_tar` extracts below `path`.
+/// Chunks go through `put_chunk` like any other upload.
+#[update]
+fn begin_tar_import(path: String) -> FsResult<u64> {
+    let root = with_fs(|fs| {
+        let root = canonical(fs, &path)?;
+        acl::check_tree(fs, &root, Permission::Write)?;
+        Ok(root)
+    })?;
+    upload::begin(UploadTarget::Tar {
+        root,
+        next_header: 0,
+        written: 0,
+    })
+}
+
+/// Extracts the tar archive staged in upload `id`. Directories and regular
+/// files are extracted with their mtime; members FAT can't hold, such as
+/// links or names with reserved characters, are reported as skipped. Big
+/// archives take several calls, each continuing where the last one stopped,
+/// until `complete` is set and the upload is closed. `sha256` is checked
+/// before the first member is extracted.
+#[update]
+fn import_tar(id: u64, sha256: Option<ByteBuf>) -> FsResult<TarImportReport> {
+    let report = upload::with_session(id, |session| {
+        let (target, next_header, written) = match &mut session.target {
+            UploadTarget::Tar {
+                root,
+                next_header,
+                written,
+            } => (root, next_header, written),
+            _ => {
+                return Err(FsError::InvalidArgument(format!(
+                    "Upload {} is not a tar archive",
+                    id
+                )))
+            }
+        };
+        if *next_header == 0 && *written == 0 {
+            upload::verify_checksum(&session.data, sha256)?;
+        }
+
+        with_fs(|fs| {
+            acl::check_tree(fs, target, Permission::Write)?;
+            home::ensure(fs, target)?;
+            let root = target
+                .iter()
+                .try_fold(fs.root_dir(), |dir, name| open_or_create_dir(&dir, name))?;
+
+            // Whatever was extracted before an error stays, so it is recorded either way.
+            let mut changes = vec![];
+            let result = unpack(
+                &session.data,
+                next_header,
+                written,
+                fs,
+                &root,
+                target,
+                &mut changes,
+            );
+            record(fs, &changes);
+            result
+        })
+    })?;
+
+    if report.complete {
+        upload::close(id);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header_checksum(header: &[u8]) -> Option<u64> {
+        parse_number(&header[148..156])
+    }
+
+    #[test]
+    fn header_round_trips_through_the_parser() {
+        let mut out = vec![];
+        write_header(&mut out, "docs/readme.txt", REGULAR, 1234, 1_600_000_000);
+        assert_eq!(out.len(), BLOCK_SIZE);
+
+        let header = &out[..];
+        assert_eq!(parse_string(&header[..100]), "docs/readme.txt");
+        assert_eq!(parse_number(&header[124..136]), Some(1234));
+        assert_eq!(parse_number(&header[136..148]), Some(1_600_000_000));
+        assert_eq!(header[156], REGULAR);
+        assert_eq!(&header[257..263], b"ustar\0");
+        assert_eq!(header_checksum(header), Some(checksum(header)));
+    }
+
+    #[test]
+    fn checksum_covers_the_header_but_not_its_own_field() {
+        let mut out = vec![];
+        write_header(&mut out, "dir/", DIRECTORY, 0, 0);
+        let expected = checksum(&out);
+        assert_eq!(header_checksum(&out), Some(expected));
+
+        out[148..156].copy_from_slice(&[0; 8]);
+        assert_eq!(checksum(&out), expected);
+        out[0] ^= 1;
+        assert_ne!(checksum(&out), expected);
+    }
+
+    #[test]
+    fn long_names_use_the_ustar_prefix() {
+        let dir = "d".repeat(120);
+        let name = format!("{}/file.txt", dir);
+        assert_eq!(split_name(&name), Some((dir.as_str(), "file.txt")));
+
+        let mut out = vec![];
+        write_header(&mut out, &name, REGULAR, 0, 0);
+        assert_eq!(out.len(), BLOCK_SIZE);
+        assert_eq!(parse_string(&out[345..500]), dir);
+        assert_eq!(parse_string(&out[..100]), "file.txt");
+    }
+
+    #[test]
+    fn names_beyond_ustar_get_a_pax_header() {
+        let name = format!("{}/{}", "a".repeat(200), "b".repeat(101));
+        assert_eq!(split_name(&name), None);
+
+        let mut out = vec![];
+        write_header(&mut out, &name, REGULAR, 0, 0);
+        assert_eq!(out[156], PAX_HEADER);
+        let size = parse_number(&out[124..136]).unwrap() as usize;
+        let records = &out[BLOCK_SIZE..BLOCK_SIZE + size];
+        assert_eq!(pax_path(records), Some(name));
+
+        // The record length counts its own digits.
+        let record = std::str::from_utf8(records).unwrap();
+        let (len, _) = record.split_once(' ').unwrap();
+        assert_eq!(len.parse::<usize>().unwrap(), record.len());
+        assert_eq!(out.len(), 3 * BLOCK_SIZE);
+    }
+
+    #[test]
+    fn numbers_in_octal_and_base_256() {
+        assert_eq!(parse_number(b"0000644\0"), Some(0o644));
+        assert_eq!(parse_number(b"   \0    "), Some(0));
+        assert_eq!(parse_number(b"12x\0"), None);
+        assert_eq!(
+            parse_number(&[0x80, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0]),
+            Some(0x1_0000)
+        );
+    }
+}
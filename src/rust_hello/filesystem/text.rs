@@ -0,0 +1,343 @@
+use std::io::{BufRead, BufReader, Read};
+
+use candid::{CandidType, Deserialize};
+use fatfs::{Seek, SeekFrom};
+use ic_cdk_macros::{query, update};
+
+use super::{
+    acl::{self, Permission},
+    open_file, open_parent, system, with_fs, File, FileSystem, FsError, FsResult,
+};
+
+const CONFIG_FILE: &str = "max_response_size";
+
+const DEFAULT_MAX_RESPONSE_SIZE: u64 = 1024 * 1024;
+
+// Replies over 2 MiB are rejected, so leave some room for the candid encoding.
+const MAX_RESPONSE_SIZE_LIMIT: u64 = 2 * 1024 * 1024 - 64 * 1024;
+
+const TAIL_CHUNK_SIZE: u64 = 64 * 1024;
+
+// Skipped lines are read in pieces of this size, so none has to fit in memory.
+const SKIP_CHUNK_SIZE: u64 = 64 * 1024;
+
+// What a line costs on top of its bytes, about its length prefix in the
+// candid reply, so files made of empty lines still run out of budget.
+const LINE_OVERHEAD: u64 = 4;
+
+/// Lines of a file, without their line endings. `truncated` is set when the
+/// response size limit cut the result short.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Lines {
+    pub lines: Vec<String>,
+    pub truncated: bool,
+}
+
+/// Text read by `cat_at`. `next_offset` is where the following read
+/// continues, as the text may be shorter or longer than the bytes consumed.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TextChunk {
+    pub text: String,
+    pub next_offset: u64,
+}
+
+/// The most bytes of file contents a read returns in one response.
+pub fn max_response_size(fs: &FileSystem) -> FsResult<u64> {
+    Ok(system::load(fs, CONFIG_FILE)?.unwrap_or(DEFAULT_MAX_RESPONSE_SIZE))
+}
+
+/// What returning `line` takes out of the response size budget.
+pub fn line_cost(line: &str) -> u64 {
+    line.len() as u64 + LINE_OVERHEAD
+}
+
+fn open<'a>(fs: &'a FileSystem, path: &str) -> FsResult<File<'a>> {
+    let (dir, file_name) = open_parent(fs, path)?;
+    open_file(&dir, &file_name)
+}
+
+fn decode_line(line: &[u8]) -> String {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    String::from_utf8_lossy(line).into_owned()
+}
+
+// Reads `count` lines after the first `skip`, as many as fit in `budget` bytes.
+fn read_lines_from(
+    mut reader: impl BufRead,
+    skip: u64,
+    count: u64,
+    mut budget: u64,
+) -> FsResult<Lines> {
+    let mut result = Lines {
+        lines: vec![],
+        truncated: false,
+    };
+    let mut buf = vec![];
+    let mut line_number = 0;
+    while line_number < skip {
+        buf.clear();
+        if (&mut reader)
+            .take(SKIP_CHUNK_SIZE)
+            .read_until(b'\n', &mut buf)?
+            == 0
+        {
+            return Ok(result);
+        }
+        if buf.ends_with(b"\n") {
+            line_number += 1;
+        }
+    }
+
+    while (result.lines.len() as u64) < count {
+        buf.clear();
+        // A line longer than the budget, its line ending aside, is cut off
+        // here and then doesn't fit.
+        if (&mut reader).take(budget + 2).read_until(b'\n', &mut buf)? == 0 {
+            break;
+        }
+
+        let line = decode_line(&buf);
+        if line_cost(&line) > budget {
+            result.truncated = true;
+            break;
+        }
+        budget -= line_cost(&line);
+        result.lines.push(line);
+    }
+    Ok(result)
+}
+
+// Length of `buf` without a UTF-8 sequence cut off at its end.
+fn complete_len(buf: &[u8]) -> usize {
+    for back in 1..=buf.len().min(3) {
+        let index = buf.len() - back;
+        let byte = buf[index];
+        if byte & 0xC0 != 0x80 {
+            let needed = match byte {
+                0xC0..=0xDF => 2,
+                0xE0..=0xEF => 3,
+                0xF0..=0xF7 => 4,
+                _ => 1,
+            };
+            return if needed > back { index } else { buf.len() };
+        }
+    }
+    buf.len()
+}
+
+/// The first `n` lines of `path`, decoded lossily.
+#[query]
+fn head(path: String, n: u64) -> FsResult<Lines> {
+    with_fs(|fs| {
+        acl::check_path(fs, &path, Permission::Read)?;
+        let budget = max_response_size(fs)?;
+        let mut file = open(fs, &path)?;
+        read_lines_from(BufReader::new(&mut file), 0, n, budget)
+    })
+}
+
+/// The last `n` lines of `path`, decoded lossily. The file is read backwards
+/// from the end, so this is cheap on large files.
+#[query]
+fn tail(path: String, n: u64) -> FsResult<Lines> {
+    with_fs(|fs| {
+        acl::check_path(fs, &path, Permission::Read)?;
+        let budget = max_response_size(fs)?;
+        let mut file = open(fs, &path)?;
+        let size = file.seek(SeekFrom::End(0))?;
+
+        // Reads chunks backwards until they hold `n` line breaks in front of
+        // the last line, or the start of the file, or more than fits.
+        let mut buf = vec![];
+        let mut start = size;
+        let mut breaks = 0;
+        while breaks < n && start > 0 && (buf.len() as u64) <= budget {
+            let chunk_start = start.saturating_sub(TAIL_CHUNK_SIZE);
+            let mut chunk = vec![];
+            file.seek(SeekFrom::Start(chunk_start))?;
+            (&mut file)
+                .take(start - chunk_start)
+                .read_to_end(&mut chunk)?;
+
+            // A break that ends the file doesn't start another line.
+            let end = if start == size {
+                chunk.strip_suffix(b"\n").unwrap_or(&chunk).len()
+            } else {
+                chunk.len()
+            };
+            breaks += chunk[..end].iter().filter(|&&b| b == b'\n').count() as u64;
+
+            chunk.extend_from_slice(&buf);
+            buf = chunk;
+            start = chunk_start;
+        }
+
+        let mut result = Lines {
+            lines: vec![],
+            truncated: false,
+        };
+        if buf.is_empty() || n == 0 {
+            return Ok(result);
+        }
+
+        let body = buf.strip_suffix(b"\n").unwrap_or(&buf);
+        let mut lines: Vec<&[u8]> = body.split(|&b| b == b'\n').collect();
+        if lines.len() as u64 > n {
+            lines.drain(..lines.len() - n as usize);
+        } else if start > 0 {
+            // The first line is incomplete; stopping early means it didn't fit.
+            lines.remove(0);
+            result.truncated = true;
+        }
+
+        let mut budget = budget;
+        for line in lines.into_iter().rev() {
+            let line = decode_line(line);
+            if line_cost(&line) > budget {
+                result.truncated = true;
+                break;
+            }
+            budget -= line_cost(&line);
+            result.lines.push(line);
+        }
+        result.lines.reverse();
+        Ok(result)
+    })
+}
+
+/// `count` lines of `path` starting at line `start`, counting from 0 and
+/// decoded lossily.
+#[query]
+fn read_lines_range(path: String, start: u64, count: u64) -> FsResult<Lines> {
+    with_fs(|fs| {
+        acl::check_path(fs, &path, Permission::Read)?;
+        let budget = max_response_size(fs)?;
+        let mut file = open(fs, &path)?;
+        read_lines_from(BufReader::new(&mut file), start, count, budget)
+    })
+}
+
+/// All lines of `path`, decoded lossily. Fails for files that don't fit in
+/// one response; `read_lines_range` reads those in parts.
+#[query]
+fn read_lines(path: String) -> FsResult<Vec<String>> {
+    with_fs(|fs| {
+        acl::check_path(fs, &path, Permission::Read)?;
+        let budget = max_response_size(fs)?;
+        let mut file = open(fs, &path)?;
+        let lines = read_lines_from(BufReader::new(&mut file), 0, u64::MAX, budget)?;
+        if lines.truncated {
+            return Err(FsError::InvalidArgument(format!(
+                "{} exceeds the maximum response size of {} bytes",
+                path, budget
+            )));
+        }
+        Ok(lines.lines)
+    })
+}
+
+/// Up to `len` bytes of `path` from `offset`, decoded lossily. Reads are cut
+/// off at the maximum response size, and before a character split by the
+/// end of the read; continue from `next_offset`.
+#[query]
+fn cat_at(path: String, offset: u64, len: u64) -> FsResult<TextChunk> {
+    with_fs(|fs| {
+        acl::check_path(fs, &path, Permission::Read)?;
+        let len = len.min(max_response_size(fs)?);
+        let mut file = open(fs, &path)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut buf = vec![];
+        (&mut file).take(len).read_to_end(&mut buf)?;
+        // Only a read that stopped short of the end can split a character,
+        // and it has to consume something to make progress.
+        if buf.len() as u64 == len {
+            let complete = complete_len(&buf);
+            if complete > 0 {
+                buf.truncate(complete);
+            }
+        }
+
+        Ok(TextChunk {
+            text: String::from_utf8_lossy(&buf).into_owned(),
+            next_offset: offset + buf.len() as u64,
+        })
+    })
+}
+
+/// Sets how many bytes of file contents a read may return at most, up to
+/// a little under the 2 MiB reply limit.
+#[update]
+fn set_max_response_size(bytes: u64) -> FsResult<()> {
+    if bytes == 0 || bytes > MAX_RESPONSE_SIZE_LIMIT {
+        return Err(FsError::InvalidArgument(format!(
+            "The maximum response size must be between 1 and {} bytes",
+            MAX_RESPONSE_SIZE_LIMIT
+        )));
+    }
+
+    with_fs(|fs| {
+        acl::check(fs, &[], Permission::Admin)?;
+        system::store(fs, CONFIG_FILE, &bytes)
+    })
+}
+
+#[query]
+fn get_max_response_size() -> FsResult<u64> {
+    with_fs(max_response_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn complete_len_drops_a_split_character() {
+        let text = "aé€😀".as_bytes();
+        assert_eq!(complete_len(text), text.len());
+        assert_eq!(complete_len(&text[..2]), 1);
+        assert_eq!(complete_len(&text[..3]), 3);
+        assert_eq!(complete_len(&text[..5]), 3);
+        assert_eq!(complete_len(&text[..8]), 6);
+        assert_eq!(complete_len(&[0xFF, 0x80]), 2);
+        assert_eq!(complete_len(&[]), 0);
+    }
+
+    #[test]
+    fn read_lines_from_skips_and_stops_at_the_budget() {
+        let text = "one\ntwo\r\nthree\nfour";
+        let lines = read_lines_from(text.as_bytes(), 1, 2, 100).unwrap();
+        assert_eq!(lines.lines, vec!["two", "three"]);
+        assert!(!lines.truncated);
+
+        let lines = read_lines_from(text.as_bytes(), 0, 10, 14).unwrap();
+        assert_eq!(lines.lines, vec!["one", "two"]);
+        assert!(lines.truncated);
+
+        let lines = read_lines_from(text.as_bytes(), 3, 10, 100).unwrap();
+        assert_eq!(lines.lines, vec!["four"]);
+        let lines = read_lines_from(text.as_bytes(), 5, 10, 100).unwrap();
+        assert!(lines.lines.is_empty());
+    }
+
+    #[test]
+    fn read_lines_from_cuts_off_long_lines() {
+        let text = format!("{}\nshort\n", "x".repeat(1000));
+        let lines = read_lines_from(text.as_bytes(), 0, 10, 100).unwrap();
+        assert!(lines.lines.is_empty());
+        assert!(lines.truncated);
+
+        let lines = read_lines_from(text.as_bytes(), 1, 10, 100).unwrap();
+        assert_eq!(lines.lines, vec!["short"]);
+    }
+
+    #[test]
+    fn read_lines_from_charges_empty_lines() {
+        let text = "\n".repeat(100_000);
+        let lines = read_lines_from(text.as_bytes(), 0, u64::MAX, 40).unwrap();
+        assert_eq!(lines.lines.len(), 10);
+        assert!(lines.lines.iter().all(|line| line.is_empty()));
+        assert!(lines.truncated);
+    }
+}
@@ -0,0 +1,147 @@
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// Days between 1970-01-01 and the given proleptic Gregorian date. FAT dates
+// start in 1980, so the result is never negative.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Converts a FAT date to nanoseconds since the Unix epoch at midnight UTC,
+/// the same scale as `ic_cdk::api::time`.
+pub fn date_to_nanos(date: fatfs::Date) -> u64 {
+    days_from_civil(date.year.into(), date.month.into(), date.day.into())
+        * SECONDS_PER_DAY
+        * NANOS_PER_SECOND
+}
+
+/// Converts a FAT timestamp to nanoseconds since the Unix epoch.
+pub fn to_nanos(date_time: fatfs::DateTime) -> u64 {
+    let time = date_time.time;
+    let seconds = u64::from(time.hour) * 3600 + u64::from(time.min) * 60 + u64::from(time.sec);
+
+    date_to_nanos(date_time.date) + seconds * NANOS_PER_SECOND + u64::from(time.millis) * 1_000_000
+}
+
+// Inverse of `days_from_civil`.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Converts seconds since the Unix epoch to a FAT timestamp, clamped to the
+/// years FAT can represent (1980 to 2107).
+pub fn from_unix_secs(secs: u64) -> fatfs::DateTime {
+    let min = days_from_civil(1980, 1, 1) * SECONDS_PER_DAY;
+    let max = days_from_civil(2107, 12, 31) * SECONDS_PER_DAY + SECONDS_PER_DAY - 1;
+    let secs = secs.clamp(min, max);
+
+    let (year, month, day) = civil_from_days(secs / SECONDS_PER_DAY);
+    let secs_of_day = secs % SECONDS_PER_DAY;
+    fatfs::DateTime::new(
+        fatfs::Date::new(year as u16, month as u16, day as u16),
+        fatfs::Time::new(
+            (secs_of_day / 3600) as u16,
+            (secs_of_day / 60 % 60) as u16,
+            (secs_of_day % 60) as u16,
+            0,
+        ),
+    )
+}
+
+/// Converts a FAT timestamp to seconds since the Unix epoch.
+pub fn to_unix_secs(date_time: fatfs::DateTime) -> u64 {
+    to_nanos(date_time) / NANOS_PER_SECOND
+}
+
+/// Builds a FAT timestamp from calendar fields, as long as they describe a
+/// time FAT can represent.
+pub fn from_civil(
+    year: u16,
+    month: u16,
+    day: u16,
+    hour: u16,
+    min: u16,
+    sec: u16,
+) -> Option<fatfs::DateTime> {
+    let valid = (1980..=2107).contains(&year)
+        && (1..=12).contains(&month)
+        && (1..=31).contains(&day)
+        && hour < 24
+        && min < 60
+        && sec < 60;
+
+    valid.then(|| {
+        fatfs::DateTime::new(
+            fatfs::Date::new(year, month, day),
+            fatfs::Time::new(hour, min, sec, 0),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn date_to_nanos_counts_days_since_the_epoch() {
+        let nanos = |year, month, day| date_to_nanos(fatfs::Date::new(year, month, day));
+        assert_eq!(nanos(1980, 1, 1), 315_532_800 * NANOS_PER_SECOND);
+        assert_eq!(nanos(2000, 2, 29), 951_782_400 * NANOS_PER_SECOND);
+        assert_eq!(nanos(2000, 3, 1), 951_868_800 * NANOS_PER_SECOND);
+    }
+
+    #[test]
+    fn to_nanos_adds_the_time_of_day() {
+        let date_time = fatfs::DateTime::new(
+            fatfs::Date::new(2021, 6, 15),
+            fatfs::Time::new(13, 45, 30, 250),
+        );
+        assert_eq!(
+            to_nanos(date_time),
+            1_623_764_730 * NANOS_PER_SECOND + 250_000_000
+        );
+    }
+
+    #[test]
+    fn civil_dates_round_trip() {
+        for &(year, month, day) in &[(1970, 1, 1), (1980, 1, 1), (2000, 2, 29), (2107, 12, 31)] {
+            assert_eq!(
+                civil_from_days(days_from_civil(year, month, day)),
+                (year, month, day)
+            );
+        }
+        for days in (3_652..50_000).step_by(97) {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn unix_seconds_round_trip_within_the_fat_range() {
+        for &secs in &[315_532_800, 951_825_598, 1_623_764_730, 4_354_819_198] {
+            assert_eq!(to_unix_secs(from_unix_secs(secs)), secs);
+        }
+        // FAT has nothing before 1980.
+        assert_eq!(to_unix_secs(from_unix_secs(0)), 315_532_800);
+    }
+}
@@ -0,0 +1,296 @@
+use std::{cell::RefCell, collections::BTreeMap};
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::{heartbeat, query, update};
+
+use super::{
+    acl::{self, PathAcl, Permission},
+    canonicalize, find_entry, home, open_parent,
+    path::{self, SYSTEM_DIR},
+    record, system,
+    tree::{self, Visit},
+    with_fs, Change, Dir, DirEntry, FileSystem, FsError, FsResult,
+};
+
+// Below the system directory, one directory per owner holding its trashed
+// entries, each named after its id.
+const TRASH_DIR: &str = "trash";
+const TRASH_FILE: &str = "trash_index";
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+// How often the heartbeat looks for expired entries.
+const PURGE_INTERVAL: u64 = 60 * 60 * NANOS_PER_SECOND;
+
+// Entries a single call purges at most, so large trashes don't run into the
+// instruction limit.
+const PURGE_BATCH: usize = 100;
+
+/// What a trashed entry and everything below it count against the quota of
+/// the home it was deleted from.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TrashCharge {
+    pub bytes: u64,
+    pub files: u64,
+}
+
+/// An entry moved to the trash by `rm`. `deleted_at` is in nanoseconds
+/// since the epoch. Entries deleted from a home belong to its owner and stay
+/// charged to it, the others belong to whoever deleted them.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TrashEntry {
+    pub id: u64,
+    pub owner: Principal,
+    pub path: String,
+    pub deleted_at: u64,
+    pub is_dir: bool,
+    pub size: u64,
+    pub charged: Option<TrashCharge>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+struct Trash {
+    last_id: u64,
+    // Seconds entries stay in the trash before the heartbeat purges them.
+    retention: Option<u64>,
+    entries: Vec<TrashEntry>,
+    // ACLs that were set on a trashed entry or below it, by entry id, to be
+    // put back when it is restored.
+    acls: BTreeMap<u64, Vec<PathAcl>>,
+}
+
+thread_local! {
+    static LAST_PURGE: RefCell<u64> = RefCell::new(0);
+}
+
+fn load(fs: &FileSystem) -> FsResult<Trash> {
+    Ok(system::load(fs, TRASH_FILE)?.unwrap_or_default())
+}
+
+fn store(fs: &FileSystem, trash: &Trash) -> FsResult<()> {
+    system::store(fs, TRASH_FILE, trash)
+}
+
+fn owner_dir<'a>(fs: &'a FileSystem, owner: Principal) -> FsResult<Dir<'a>> {
+    system::dir(fs, &[TRASH_DIR, &owner.to_text()])
+}
+
+/// Moves the entry at `components`, `name` in `dir`, into the trash instead
+/// of deleting it: that of the home's owner if it lies in a home, so it keeps
+/// counting against their quota, otherwise the caller's. A directory goes
+/// with everything below it in a single rename, and the ACLs set on any of it
+/// are kept for the restore.
+pub fn discard(fs: &FileSystem, dir: &Dir, name: &str, components: &[String]) -> FsResult<()> {
+    let entry = find_entry(dir, name)?.ok_or(FsError::NotFound)?;
+    let owner = home::home_owner(components).unwrap_or_else(ic_cdk::caller);
+    let charged = home::held(fs, components)?.map(|(bytes, files)| TrashCharge { bytes, files });
+    let mut trash = load(fs)?;
+    trash.last_id += 1;
+    let id = trash.last_id;
+
+    dir.rename(name, &owner_dir(fs, owner)?, &id.to_string())?;
+    let acls = acl::detach(fs, components)?;
+    if !acls.is_empty() {
+        trash.acls.insert(id, acls);
+    }
+    trash.entries.push(TrashEntry {
+        id,
+        owner,
+        path: path::join(components),
+        deleted_at: ic_cdk::api::time(),
+        is_dir: entry.is_dir(),
+        size: entry.len(),
+        charged,
+    });
+    store(fs, &trash)?;
+    home::forget_trashed(owner);
+    Ok(())
+}
+
+/// What `owner`'s trash holds that still counts against their home, in bytes
+/// and entries.
+pub fn charged(fs: &FileSystem, owner: Principal) -> FsResult<(u64, u64)> {
+    Ok(load(fs)?
+        .entries
+        .iter()
+        .filter(|entry| entry.owner == owner)
+        .filter_map(|entry| entry.charged.as_ref())
+        .fold((0, 0), |(bytes, files), charge| {
+            (
+                bytes.saturating_add(charge.bytes),
+                files.saturating_add(charge.files),
+            )
+        }))
+}
+
+// Permanently deletes up to `PURGE_BATCH` entries that `filter` selects and
+// returns how many are gone. An entry too large to delete in one go stays
+// listed until a later call finishes it.
+fn purge(
+    fs: &FileSystem,
+    trash: &mut Trash,
+    filter: impl Fn(&TrashEntry) -> bool,
+) -> FsResult<u64> {
+    let selected: Vec<(u64, Principal)> = trash
+        .entries
+        .iter()
+        .filter(|entry| filter(entry))
+        .take(PURGE_BATCH)
+        .map(|entry| (entry.id, entry.owner))
+        .collect();
+
+    let mut purged = 0;
+    for (id, owner) in selected {
+        let components = vec![
+            SYSTEM_DIR.to_string(),
+            TRASH_DIR.to_string(),
+            owner.to_text(),
+            id.to_string(),
+        ];
+        let complete = match tree::remove_tree(fs, &components, &mut vec![]) {
+            Err(FsError::NotFound) => true,
+            result => result?,
+        };
+        if !complete {
+            break;
+        }
+
+        trash.entries.retain(|entry| entry.id != id);
+        trash.acls.remove(&id);
+        home::forget_trashed(owner);
+        purged += 1;
+    }
+    Ok(purged)
+}
+
+fn expired(entry: &TrashEntry, seconds: u64, now: u64) -> bool {
+    entry
+        .deleted_at
+        .saturating_add(seconds.saturating_mul(NANOS_PER_SECOND))
+        <= now
+}
+
+/// The caller's trashed entries, oldest first, including those others
+/// deleted from the caller's home.
+#[query]
+fn trash_list() -> FsResult<Vec<TrashEntry>> {
+    with_fs(|fs| {
+        let caller = ic_cdk::caller();
+        Ok(load(fs)?
+            .entries
+            .into_iter()
+            .filter(|entry| entry.owner == caller)
+            .collect())
+    })
+}
+
+// The changes restoring `entry` at `components` makes: it and, for a
+// directory, everything below it come back.
+fn restored(entry: &DirEntry, components: &[String]) -> FsResult<Vec<Change>> {
+    if !entry.is_dir() {
+        return Ok(vec![Change::Written(components.to_vec())]);
+    }
+
+    let mut changes = vec![Change::Created(components.to_vec())];
+    let mut visit = |entry: &DirEntry, components: &[String], _depth: u32| -> FsResult<Visit> {
+        changes.push(if entry.is_dir() {
+            Change::Created(components.to_vec())
+        } else {
+            Change::Written(components.to_vec())
+        });
+        Ok(Visit::Continue)
+    };
+    tree::walk_after(&entry.to_dir(), components, 1, None, &mut visit)?;
+    Ok(changes)
+}
+
+/// Moves the caller's trashed entry `id` back to where it was deleted from
+/// and returns that path. Fails with `AlreadyExists` if something took its
+/// place in the meantime; its parent directory has to exist.
+#[update]
+fn trash_restore(id: u64) -> FsResult<String> {
+    with_fs(|fs| {
+        let caller = ic_cdk::caller();
+        let mut trash = load(fs)?;
+        let position = trash
+            .entries
+            .iter()
+            .position(|entry| entry.id == id && entry.owner == caller)
+            .ok_or(FsError::NotFound)?;
+        let entry = trash.entries.remove(position);
+
+        let components = canonicalize(fs, path::normalize(&entry.path)?)?;
+        acl::check(fs, &components, Permission::Write)?;
+        home::ensure(fs, &components)?;
+        let (dir, name) = open_parent(fs, &entry.path)?;
+        if find_entry(&dir, &name)?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        // Whatever comes back to a home was charged to it all along, and
+        // anything else goes back outside of homes.
+        let trash_dir = owner_dir(fs, caller)?;
+        find_entry(&trash_dir, &id.to_string())?.ok_or(FsError::NotFound)?;
+        trash_dir.rename(&id.to_string(), &dir, &name)?;
+        acl::attach(fs, trash.acls.remove(&id).unwrap_or_default())?;
+        store(fs, &trash)?;
+        home::forget_trashed(caller);
+
+        let restored_entry = find_entry(&dir, &name)?.ok_or(FsError::NotFound)?;
+        record(fs, &restored(&restored_entry, &components)?);
+        Ok(entry.path)
+    })
+}
+
+/// Permanently deletes the caller's trashed entries that were deleted more
+/// than `older_than` seconds ago and returns how many are gone. Large
+/// trashes take several calls; call again until it returns 0.
+#[update]
+fn trash_purge(older_than: u64) -> FsResult<u64> {
+    with_fs(|fs| {
+        let caller = ic_cdk::caller();
+        let now = ic_cdk::api::time();
+        let mut trash = load(fs)?;
+        let purged = purge(fs, &mut trash, |entry| {
+            entry.owner == caller && expired(entry, older_than, now)
+        })?;
+        store(fs, &trash)?;
+        Ok(purged)
+    })
+}
+
+/// Sets how many seconds trashed entries are kept before they are purged
+/// automatically, or with `None` keeps them until purged by hand. Needs
+/// `Admin` on the root.
+#[update]
+fn set_trash_retention(seconds: Option<u64>) -> FsResult<()> {
+    with_fs(|fs| {
+        acl::check(fs, &[], Permission::Admin)?;
+        let mut trash = load(fs)?;
+        trash.retention = seconds;
+        store(fs, &trash)
+    })
+}
+
+// Purges expired entries of all owners now and then, a batch at a time.
+#[heartbeat]
+fn purge_expired_trash() {
+    let now = ic_cdk::api::time();
+    if LAST_PURGE.with(|last| last.borrow().saturating_add(PURGE_INTERVAL)) > now {
+        return;
+    }
+    LAST_PURGE.with(|last| *last.borrow_mut() = now);
+
+    // An unmounted volume or a failed purge is simply retried next time.
+    let _ = with_fs(|fs| {
+        let mut trash = load(fs)?;
+        let retention = match trash.retention {
+            Some(retention) => retention,
+            None => return Ok(()),
+        };
+        if purge(fs, &mut trash, |entry| expired(entry, retention, now))? > 0 {
+            store(fs, &trash)?;
+        }
+        Ok(())
+    });
+}
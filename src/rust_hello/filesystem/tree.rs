@@ -0,0 +1,368 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::{query, update};
+
+use super::{
+    acl::{self, Acls, Permission},
+    canonical, find_entry, home, open_dir, open_dir_components, path, record, stat, trash, with_fs,
+    Change, Dir, DirEntry, FileSystem, FsError, FsResult,
+};
+
+// Directory entries a single call may visit before it stops and reports that
+// it is incomplete, so huge trees don't run into the instruction limit.
+const ENTRY_BUDGET: usize = 10_000;
+
+// Entries `walk` may look at in one call, including those it leaves out
+// because the caller can't read them.
+const VISIT_BUDGET: usize = 4 * ENTRY_BUDGET;
+
+// Entries of the root `remove_all` moves to the trash in one call, each of
+// which rewrites the trash index.
+const DISCARD_BATCH: usize = 100;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RemoveReport {
+    pub removed: Vec<String>,
+    pub complete: bool,
+    // Where a dry run that ran out of budget continues.
+    pub next: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct WalkEntry {
+    pub path: String,
+    pub depth: u32,
+    pub info: stat::FileInfo,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Walk {
+    pub entries: Vec<WalkEntry>,
+    pub next: Option<String>,
+}
+
+pub enum Visit {
+    Continue,
+    // Go on, but leave out what is below the visited directory.
+    Prune,
+    // Stop, and resume after the visited entry.
+    Stop,
+}
+
+/// Visits the subtree below `dir` depth first in name order, skipping the
+/// entries up to and including `after`. Returns the last visited path when
+/// `visit` stopped the walk.
+pub fn walk_after(
+    dir: &Dir,
+    dir_path: &[String],
+    depth: u32,
+    after: Option<&[String]>,
+    visit: &mut dyn FnMut(&DirEntry, &[String], u32) -> FsResult<Visit>,
+) -> FsResult<Option<Vec<String>>> {
+    let mut entries = vec![];
+    for entry in dir.iter() {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name != "." && name != ".." && !path::is_system(dir_path, &name) {
+            entries.push((name, entry));
+        }
+    }
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (name, entry) in entries {
+        let mut components = dir_path.to_vec();
+        components.push(name);
+
+        // Entries sort before their children, so everything up to `after`
+        // is either an ancestor of it, which is descended into, or done.
+        let resume_below = match after {
+            Some(after) if components.as_slice() <= after => {
+                if !after.starts_with(&components) {
+                    continue;
+                }
+                Some(after)
+            }
+            _ => match visit(&entry, &components, depth)? {
+                Visit::Continue => None,
+                Visit::Prune => continue,
+                Visit::Stop => return Ok(Some(components)),
+            },
+        };
+
+        if entry.is_dir() {
+            if let Some(last) =
+                walk_after(&entry.to_dir(), &components, depth + 1, resume_below, visit)?
+            {
+                return Ok(Some(last));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Cursors of `walk`, `cp` and dry runs of `remove_all` are the path of the
+/// last entry they got to, which has to lie below `root`.
+pub fn decode_cursor(cursor: &str, root: &[String]) -> FsResult<Vec<String>> {
+    let components = path::normalize(cursor)?;
+    if !components.starts_with(root) || components.len() == root.len() {
+        return Err(FsError::InvalidArgument(format!(
+            "Invalid cursor: {}",
+            cursor
+        )));
+    }
+    Ok(components)
+}
+
+/// Returns the entries of `dir` other than `.` and `..`, sorted by name.
+fn children(dir: &Dir) -> FsResult<Vec<(String, bool)>> {
+    let mut children = vec![];
+    for entry in dir.iter() {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name != "." && name != ".." {
+            children.push((name, entry.is_dir()));
+        }
+    }
+
+    children.sort();
+    Ok(children)
+}
+
+fn child_path(parent: &[String], name: &str) -> Vec<String> {
+    let mut components = parent.to_vec();
+    components.push(name.to_string());
+    components
+}
+
+/// Creates `path` along with any missing parent directories.
+#[update]
+fn mkdir_all(path: String) -> FsResult<()> {
+    with_fs(|fs| {
+        let target = canonical(fs, &path)?;
+        acl::check(fs, &target, Permission::Write)?;
+        home::ensure(fs, &target)?;
+        home::reserve_dir(fs, &target)?;
+        let mut dir = fs.root_dir();
+        let mut components = vec![];
+        let mut changes = vec![];
+        for name in target {
+            components.push(name.clone());
+            dir = match find_entry(&dir, &name)? {
+                Some(entry) if entry.is_dir() => entry.to_dir(),
+                Some(_) => return Err(FsError::NotADirectory),
+                None => {
+                    changes.push(Change::Created(components.clone()));
+                    dir.create_dir(&name)?
+                }
+            };
+        }
+        record(fs, &changes);
+        Ok(())
+    })
+}
+
+// Removes everything below `dir` depth first. Returns false once the budget
+// runs out before the directory is empty.
+fn remove_children(
+    dir: &Dir,
+    dir_path: &[String],
+    budget: &mut usize,
+    removed: &mut Vec<String>,
+) -> FsResult<bool> {
+    for (name, is_dir) in children(dir)? {
+        if path::is_system(dir_path, &name) {
+            continue;
+        }
+        if *budget == 0 {
+            return Ok(false);
+        }
+
+        let components = child_path(dir_path, &name);
+        if is_dir && !remove_children(&open_dir(dir, &name)?, &components, budget, removed)? {
+            return Ok(false);
+        }
+
+        *budget -= 1;
+        dir.remove(&name)?;
+        removed.push(path::join(&components));
+    }
+    Ok(true)
+}
+
+/// Removes `components` and everything below it, or just the contents of the
+/// root. Returns false once the budget runs out first.
+pub fn remove_tree(
+    fs: &FileSystem,
+    components: &[String],
+    removed: &mut Vec<String>,
+) -> FsResult<bool> {
+    let mut budget = ENTRY_BUDGET;
+    let (name, parent_path) = match components.split_last() {
+        Some(split) => split,
+        None => return remove_children(&fs.root_dir(), &[], &mut budget, removed),
+    };
+
+    let parent = open_dir_components(fs, parent_path)?;
+    let entry = find_entry(&parent, name)?.ok_or(FsError::NotFound)?;
+    if entry.is_dir() && !remove_children(&entry.to_dir(), components, &mut budget, removed)? {
+        return Ok(false);
+    }
+
+    if budget == 0 {
+        return Ok(false);
+    }
+    parent.remove(name)?;
+    removed.push(path::join(components));
+    Ok(true)
+}
+
+// Lists what `remove_all` would remove, the subtree in depth-first name
+// order followed by `components` itself, continuing after `after`.
+fn list_tree(
+    fs: &FileSystem,
+    components: &[String],
+    after: Option<&[String]>,
+) -> FsResult<RemoveReport> {
+    let dir = match components.split_last() {
+        Some((name, parent)) => {
+            let entry =
+                find_entry(&open_dir_components(fs, parent)?, name)?.ok_or(FsError::NotFound)?;
+            entry.is_dir().then(|| entry.to_dir())
+        }
+        None => Some(fs.root_dir()),
+    };
+
+    let mut removed = vec![];
+    let mut visit = |_: &DirEntry, components: &[String], _depth: u32| -> FsResult<Visit> {
+        removed.push(path::join(components));
+        if removed.len() >= ENTRY_BUDGET {
+            Ok(Visit::Stop)
+        } else {
+            Ok(Visit::Continue)
+        }
+    };
+    let last = match dir {
+        Some(dir) => walk_after(&dir, components, 1, after, &mut visit)?,
+        None => None,
+    };
+
+    if last.is_none() && !components.is_empty() {
+        removed.push(path::join(components));
+    }
+    Ok(RemoveReport {
+        removed,
+        complete: last.is_none(),
+        next: last.map(|last| path::join(&last)),
+    })
+}
+
+/// Moves `path` and everything below it into the trash, see
+/// `trash_restore`, and reports it as removed. Removing the root trashes its
+/// entries one by one, a bounded number per call; call again until
+/// `complete` is true. With `dry_run` nothing is touched and the report
+/// lists everything that would go, a bounded number at a time; pass `next`
+/// back as `cursor` to continue.
+#[update]
+fn remove_all(path: String, dry_run: bool, cursor: Option<String>) -> FsResult<RemoveReport> {
+    with_fs(|fs| {
+        let components = canonical(fs, &path)?;
+        acl::check_tree(fs, &components, Permission::Write)?;
+        if dry_run {
+            let after = cursor
+                .map(|cursor| decode_cursor(&cursor, &components))
+                .transpose()?;
+            return list_tree(fs, &components, after.as_deref());
+        }
+        if cursor.is_some() {
+            return Err(FsError::InvalidArgument(
+                "Only dry runs take a cursor".to_string(),
+            ));
+        }
+
+        let (dir, targets) = match components.split_last() {
+            Some((name, parent)) => (open_dir_components(fs, parent)?, vec![name.clone()]),
+            None => {
+                let root = fs.root_dir();
+                let names = children(&root)?
+                    .into_iter()
+                    .map(|(name, _)| name)
+                    .filter(|name| !path::is_system(&[], name))
+                    .collect();
+                (root, names)
+            }
+        };
+        let parent = &components[..components.len().saturating_sub(1)];
+
+        let mut removed = vec![];
+        let mut changes = vec![];
+        let mut result = Ok(());
+        for name in targets.iter().take(DISCARD_BATCH) {
+            let target = child_path(parent, name);
+            result = trash::discard(fs, &dir, name, &target);
+            if result.is_err() {
+                break;
+            }
+            removed.push(path::join(&target));
+            changes.push(Change::Removed(target));
+        }
+
+        // Whatever was trashed before an error is gone all the same.
+        record(fs, &changes);
+        result?;
+        Ok(RemoveReport {
+            removed,
+            complete: targets.len() <= DISCARD_BATCH,
+            next: None,
+        })
+    })
+}
+
+/// Lists the subtree below `path` in depth-first name order. Direct children
+/// have depth 1 and nothing deeper than `max_depth` is listed; entries the
+/// caller can't read are left out. Large trees come a bounded number of
+/// entries at a time; pass `next` back as `cursor` to continue.
+#[query]
+fn walk(path: String, max_depth: Option<u32>, cursor: Option<String>) -> FsResult<Walk> {
+    with_fs(|fs| {
+        let components = canonical(fs, &path)?;
+        let acls = Acls::load(fs)?;
+        acls.check(&components, Permission::Read)?;
+        let after = cursor
+            .map(|cursor| decode_cursor(&cursor, &components))
+            .transpose()?;
+        let dir = open_dir_components(fs, &components)?;
+        if max_depth == Some(0) {
+            return Ok(Walk {
+                entries: vec![],
+                next: None,
+            });
+        }
+
+        let caller = ic_cdk::caller();
+        let mut entries = vec![];
+        let mut budget = VISIT_BUDGET;
+        let mut visit = |entry: &DirEntry, components: &[String], depth: u32| -> FsResult<Visit> {
+            budget -= 1;
+            if acls.allows(components, caller, Permission::Read) {
+                entries.push(WalkEntry {
+                    path: path::join(components),
+                    depth,
+                    info: stat::entry_info(entry),
+                });
+            }
+
+            if budget == 0 || entries.len() >= ENTRY_BUDGET {
+                Ok(Visit::Stop)
+            } else if max_depth.map_or(false, |max_depth| depth >= max_depth) {
+                Ok(Visit::Prune)
+            } else {
+                Ok(Visit::Continue)
+            }
+        };
+
+        let last = walk_after(&dir, &components, 1, after.as_deref(), &mut visit)?;
+        Ok(Walk {
+            entries,
+            next: last.map(|last| path::join(&last)),
+        })
+    })
+}
@@ -0,0 +1,220 @@
+use std::{cell::RefCell, collections::BTreeMap};
+
+use candid::Principal;
+use ic_cdk_macros::update;
+use sha2::{Digest, Sha256};
+
+use super::{
+    acl::{self, Permission},
+    canonical, home, open_parent, path, record, replace_file, with_fs, Change, FsError, FsResult,
+};
+
+// Upper bound for the data a caller stages across all its uploads, so a bad
+// offset can't exhaust the heap.
+const MAX_UPLOAD_SIZE: u64 = 256 * 1024 * 1024;
+
+// Upper bound for the data all callers stage together.
+const MAX_STAGED_SIZE: u64 = 1024 * 1024 * 1024;
+
+// Uploads a caller may have open at once.
+const MAX_SESSIONS: usize = 8;
+
+// Sessions untouched for this long are dropped when the next one begins.
+const SESSION_TIMEOUT: u64 = 60 * 60 * 1_000_000_000;
+
+/// Where a staged upload ends up once it is committed.
+pub enum UploadTarget {
+    File(String),
+    Volume,
+    // Extracted below `root`, `next_entry` is where extraction continues and
+    // `written` how much of that entry's data is already extracted.
+    Zip {
+        root: Vec<String>,
+        next_entry: usize,
+        written: u64,
+    },
+    // Extracted below `root`, `next_header` is where extraction continues and
+    // `written` how much of that member's data is already extracted.
+    Tar {
+        root: Vec<String>,
+        next_header: usize,
+        written: u64,
+    },
+}
+
+pub struct UploadSession {
+    owner: Principal,
+    // When a call last used the session, in nanoseconds since the epoch.
+    last_used: u64,
+    pub target: UploadTarget,
+    pub data: Vec<u8>,
+}
+
+thread_local! {
+    static UPLOADS: RefCell<BTreeMap<u64, UploadSession>> = RefCell::default();
+    static NEXT_UPLOAD_ID: RefCell<u64> = RefCell::new(0);
+}
+
+pub fn with_session<T>(id: u64, f: impl FnOnce(&mut UploadSession) -> FsResult<T>) -> FsResult<T> {
+    UPLOADS.with(|uploads| {
+        let mut uploads = uploads.borrow_mut();
+        let session = uploads
+            .get_mut(&id)
+            .filter(|session| session.owner == ic_cdk::caller())
+            .ok_or(FsError::NotFound)?;
+        session.last_used = ic_cdk::api::time();
+        f(session)
+    })
+}
+
+// Drops the sessions of all callers that have expired.
+fn drop_expired(uploads: &mut BTreeMap<u64, UploadSession>) {
+    let now = ic_cdk::api::time();
+    uploads.retain(|_, session| session.last_used.saturating_add(SESSION_TIMEOUT) > now);
+}
+
+// Bytes staged in the live sessions other than `except`: by `owner`, and by
+// all callers.
+fn staged(owner: Principal, except: u64) -> (u64, u64) {
+    UPLOADS.with(|uploads| {
+        let mut uploads = uploads.borrow_mut();
+        drop_expired(&mut uploads);
+        let (mut by_owner, mut total) = (0, 0);
+        for (_, session) in uploads.iter().filter(|(id, _)| **id != except) {
+            let len = session.data.len() as u64;
+            total += len;
+            if session.owner == owner {
+                by_owner += len;
+            }
+        }
+        (by_owner, total)
+    })
+}
+
+/// Opens an upload session for the caller and returns its id. Expired
+/// sessions of all callers are dropped first; a caller can't have more than
+/// `MAX_SESSIONS` open.
+pub fn begin(target: UploadTarget) -> FsResult<u64> {
+    let owner = ic_cdk::caller();
+    let now = ic_cdk::api::time();
+    UPLOADS.with(|uploads| {
+        let mut uploads = uploads.borrow_mut();
+        drop_expired(&mut uploads);
+        if uploads
+            .values()
+            .filter(|session| session.owner == owner)
+            .count()
+            >= MAX_SESSIONS
+        {
+            return Err(FsError::InvalidArgument(format!(
+                "At most {} uploads can be open at once",
+                MAX_SESSIONS
+            )));
+        }
+
+        let id = NEXT_UPLOAD_ID.with(|next| {
+            let mut next = next.borrow_mut();
+            *next += 1;
+            *next
+        });
+        uploads.insert(
+            id,
+            UploadSession {
+                owner,
+                last_used: now,
+                target,
+                data: vec![],
+            },
+        );
+        Ok(id)
+    })
+}
+
+pub fn close(id: u64) {
+    UPLOADS.with(|uploads| uploads.borrow_mut().remove(&id));
+}
+
+/// Checks the staged data against an expected SHA-256 digest, if there is one.
+pub fn verify_checksum(data: &[u8], sha256: Option<serde_bytes::ByteBuf>) -> FsResult<()> {
+    match sha256 {
+        Some(expected) if Sha256::digest(data).as_slice() != expected.as_slice() => {
+            Err(FsError::Io("Checksum mismatch".to_string()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Starts a chunked upload to `path` and returns the session id used by
+/// `put_chunk`, `commit_upload` and `abort_upload`.
+#[update]
+fn begin_upload(path: String) -> FsResult<u64> {
+    path::split_last(&path)?;
+    with_fs(|fs| acl::check_path(fs, &path, Permission::Write))?;
+    begin(UploadTarget::File(path))
+}
+
+/// Stages `chunk` at `offset` of the upload. Chunks may arrive in any order.
+/// Fails with `NoSpace` once the caller's uploads, or those of all callers
+/// together, would hold too much.
+#[update]
+fn put_chunk(id: u64, offset: u64, chunk: serde_bytes::ByteBuf) -> FsResult<()> {
+    let (staged, total) = staged(ic_cdk::caller(), id);
+    with_session(id, |session| {
+        let end = offset
+            .checked_add(chunk.len() as u64)
+            .ok_or_else(|| FsError::InvalidArgument("Chunk offset out of range".to_string()))?;
+        let size = end.max(session.data.len() as u64);
+        if staged + size > MAX_UPLOAD_SIZE || total + size > MAX_STAGED_SIZE {
+            return Err(FsError::NoSpace);
+        }
+
+        let (offset, end) = (offset as usize, end as usize);
+        if session.data.len() < end {
+            session.data.resize(end, 0);
+        }
+        session.data[offset..end].copy_from_slice(&chunk);
+        Ok(())
+    })
+}
+
+/// Writes the staged upload to its path, replacing any existing file, and
+/// returns the file length. When `sha256` is given the staged content must
+/// match it, otherwise nothing is written and the session stays open.
+#[update]
+fn commit_upload(id: u64, sha256: Option<serde_bytes::ByteBuf>) -> FsResult<u64> {
+    with_session(id, |session| {
+        let path = match &session.target {
+            UploadTarget::File(path) => path,
+            _ => {
+                return Err(FsError::InvalidArgument(format!(
+                    "Upload {} is not a file",
+                    id
+                )))
+            }
+        };
+        verify_checksum(&session.data, sha256)?;
+
+        with_fs(|fs| {
+            let components = canonical(fs, path)?;
+            acl::check(fs, &components, Permission::Write)?;
+            home::ensure(fs, &components)?;
+            let (dir, file_name) = open_parent(fs, path)?;
+            replace_file(fs, &dir, &file_name, &components, &session.data)?;
+            record(fs, &[Change::Written(components)]);
+            Ok(())
+        })?;
+        Ok(session.data.len() as u64)
+    })
+    .map(|len| {
+        close(id);
+        len
+    })
+}
+
+/// Discards a staged upload without touching the filesystem.
+#[update]
+fn abort_upload(id: u64) -> FsResult<()> {
+    with_session(id, |_| Ok(()))?;
+    close(id);
+    Ok(())
+}
@@ -0,0 +1,329 @@
+use std::{cell::RefCell, collections::BTreeMap, io::Read};
+
+use candid::{CandidType, Deserialize, Principal};
+use fatfs::{Seek, SeekFrom};
+use ic_cdk_macros::{query, update};
+use serde_bytes::ByteBuf;
+
+use super::{
+    acl::{self, Permission},
+    canonical, find_entry, home, open_file, open_parent, path, record, replace_file, system, text,
+    with_fs, Change, FileSystem, FsError, FsResult,
+};
+
+const VERSION_FILE: &str = "versions";
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+// Leases are meant to cover an edit, not to hold on to a file for good.
+const MAX_LEASE_SECONDS: u64 = 60 * 60;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct FileVersion {
+    path: String,
+    version: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct VersionedBytes {
+    pub contents: ByteBuf,
+    pub version: u64,
+}
+
+/// An advisory lock on a path. `expires_at` is in nanoseconds since the
+/// epoch, like `ic_cdk::api::time`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Lease {
+    pub path: String,
+    pub owner: Principal,
+    pub expires_at: u64,
+}
+
+// What the version file holds. `last_version` only ever grows, even when
+// files are removed, so a path that is deleted and recreated never gets a
+// version back that it had before.
+#[derive(CandidType, Deserialize, Default)]
+struct VersionFile {
+    last_version: u64,
+    files: Vec<FileVersion>,
+}
+
+struct Versions {
+    last_version: u64,
+    // Keyed by `key`, so lookups are case-insensitive like FAT names.
+    files: BTreeMap<String, FileVersion>,
+}
+
+thread_local! {
+    // Leases are short-lived, so they are kept on the heap and don't survive
+    // upgrades.
+    static LEASES: RefCell<BTreeMap<String, Lease>> = RefCell::default();
+}
+
+fn key(components: &[String]) -> String {
+    path::join(components).to_uppercase()
+}
+
+fn load_versions(fs: &FileSystem) -> FsResult<Versions> {
+    let file: VersionFile = system::load(fs, VERSION_FILE)?.unwrap_or_default();
+    Ok(Versions {
+        last_version: file.last_version,
+        files: file
+            .files
+            .into_iter()
+            .map(|version| (version.path.to_uppercase(), version))
+            .collect(),
+    })
+}
+
+fn store_versions(fs: &FileSystem, versions: Versions) -> FsResult<()> {
+    let file = VersionFile {
+        last_version: versions.last_version,
+        files: versions.files.into_values().collect(),
+    };
+    system::store(fs, VERSION_FILE, &file)
+}
+
+// `components` itself, if tracked, and everything tracked below it.
+fn tracked(versions: &BTreeMap<String, FileVersion>, components: &[String]) -> Vec<String> {
+    let own = key(components);
+    let below = format!("{}/", own);
+    let mut keys: Vec<String> = versions
+        .range(below.clone()..)
+        .map(|(key, _)| key)
+        .take_while(|key| key.starts_with(&below))
+        .cloned()
+        .collect();
+    if versions.contains_key(&own) {
+        keys.push(own);
+    }
+    keys
+}
+
+fn next_version(versions: &mut Versions) -> FsResult<u64> {
+    let version = versions
+        .last_version
+        .checked_add(1)
+        .ok_or_else(|| FsError::Io("Ran out of file versions".to_string()))?;
+    versions.last_version = version;
+    Ok(version)
+}
+
+/// The version of the existing file at `components`: the value of a
+/// volume-wide counter when it was last written through the canister, so it
+/// grows with every write and is never reused. A file without one yet, such
+/// as one from before versions were kept, gets the next value on first sight.
+pub fn current(fs: &FileSystem, components: &[String]) -> FsResult<u64> {
+    let mut versions = load_versions(fs)?;
+    if let Some(version) = versions.files.get(&key(components)) {
+        return Ok(version.version);
+    }
+
+    let version = next_version(&mut versions)?;
+    versions.files.insert(
+        key(components),
+        FileVersion {
+            path: path::join(components),
+            version,
+        },
+    );
+    store_versions(fs, versions)?;
+    Ok(version)
+}
+
+/// Gives every written path a new version, as does everything tracked below
+/// a written directory. Versions move along with renames.
+pub fn apply(fs: &FileSystem, changes: &[Change]) -> FsResult<()> {
+    let mut versions = load_versions(fs)?;
+    let mut changed = false;
+
+    for change in changes {
+        match change {
+            Change::Written(components) => {
+                let version = next_version(&mut versions)?;
+                for tracked_key in tracked(&versions.files, components) {
+                    versions.files.get_mut(&tracked_key).unwrap().version = version;
+                }
+                versions.files.insert(
+                    key(components),
+                    FileVersion {
+                        path: path::join(components),
+                        version,
+                    },
+                );
+                changed = true;
+            }
+            Change::Created(_) => {}
+            Change::Removed(components) => {
+                for tracked_key in tracked(&versions.files, components) {
+                    versions.files.remove(&tracked_key);
+                    changed = true;
+                }
+            }
+            Change::Renamed(from, to) => {
+                // Whatever was at `to` before has been recorded as removed.
+                for tracked_key in tracked(&versions.files, from) {
+                    let mut version = versions.files.remove(&tracked_key).unwrap();
+                    let mut components = to.clone();
+                    components.extend(path::normalize(&version.path)?.into_iter().skip(from.len()));
+                    version.path = path::join(&components);
+                    versions.files.insert(key(&components), version);
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    if changed {
+        store_versions(fs, versions)?;
+    }
+    Ok(())
+}
+
+// The unexpired lease on `components`, if any.
+fn lease(components: &[String]) -> Option<Lease> {
+    let now = ic_cdk::api::time();
+    LEASES.with(|leases| {
+        let mut leases = leases.borrow_mut();
+        leases.retain(|_, lease| lease.expires_at > now);
+        leases.get(&key(components)).cloned()
+    })
+}
+
+// Fails with `Conflict` while someone other than the caller holds a lease.
+fn check_lease(components: &[String]) -> FsResult<()> {
+    match lease(components) {
+        Some(lease) if lease.owner != ic_cdk::caller() => Err(FsError::Conflict(format!(
+            "{} is locked by {} until {}",
+            lease.path,
+            lease.owner.to_text(),
+            lease.expires_at
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// The version of `path`, see `write_if`.
+#[query]
+fn version(path: String) -> FsResult<u64> {
+    with_fs(|fs| {
+        let components = canonical(fs, &path)?;
+        acl::check(fs, &components, Permission::Read)?;
+        let (dir, file_name) = open_parent(fs, &path)?;
+        open_file(&dir, &file_name)?;
+        current(fs, &components)
+    })
+}
+
+/// Up to `len` bytes of `path` from `offset`, along with the version they
+/// belong to.
+#[query]
+fn read_versioned(path: String, offset: u64, len: u64) -> FsResult<VersionedBytes> {
+    with_fs(|fs| {
+        let components = canonical(fs, &path)?;
+        acl::check(fs, &components, Permission::Read)?;
+        let len = len.min(text::max_response_size(fs)?);
+        let (dir, file_name) = open_parent(fs, &path)?;
+        let mut file = open_file(&dir, &file_name)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut buf = vec![];
+        (&mut file).take(len).read_to_end(&mut buf)?;
+        Ok(VersionedBytes {
+            contents: ByteBuf::from(buf),
+            version: current(fs, &components)?,
+        })
+    })
+}
+
+/// Replaces the contents of `path`, creating it if needed, but only if it
+/// is still at `expected_version`, 0 for a file that doesn't exist yet, and
+/// nobody else holds a lease on it.
+/// Otherwise nothing is written and the call fails with `Conflict`. Returns
+/// the new version.
+#[update]
+fn write_if(path: String, contents: ByteBuf, expected_version: u64) -> FsResult<u64> {
+    with_fs(|fs| {
+        let components = canonical(fs, &path)?;
+        acl::check(fs, &components, Permission::Write)?;
+        check_lease(&components)?;
+
+        let existing = match open_parent(fs, &path) {
+            Ok((dir, file_name)) => find_entry(&dir, &file_name)?,
+            Err(FsError::NotFound) => None,
+            Err(error) => return Err(error),
+        };
+        let version = match existing {
+            Some(entry) if entry.is_dir() => return Err(FsError::IsADirectory),
+            Some(_) => current(fs, &components)?,
+            None => 0,
+        };
+        if version != expected_version {
+            return Err(FsError::Conflict(format!(
+                "{} is at version {}, not {}",
+                path::join(&components),
+                version,
+                expected_version
+            )));
+        }
+
+        home::ensure(fs, &components)?;
+        let (dir, file_name) = open_parent(fs, &path)?;
+        replace_file(fs, &dir, &file_name, &components, &contents)?;
+        record(fs, &[Change::Written(components.clone())]);
+        current(fs, &components)
+    })
+}
+
+/// Takes or renews an advisory lease on `path` for `seconds`, at most an
+/// hour. Fails with `Conflict` while someone else holds one. Leases don't
+/// block plain writes, only `write_if` and other `lock` calls.
+#[update]
+fn lock(path: String, seconds: u64) -> FsResult<Lease> {
+    if seconds == 0 || seconds > MAX_LEASE_SECONDS {
+        return Err(FsError::InvalidArgument(format!(
+            "A lease lasts between 1 and {} seconds",
+            MAX_LEASE_SECONDS
+        )));
+    }
+
+    let components = with_fs(|fs| {
+        let components = canonical(fs, &path)?;
+        acl::check(fs, &components, Permission::Write)?;
+        Ok(components)
+    })?;
+    check_lease(&components)?;
+
+    let lease = Lease {
+        path: path::join(&components),
+        owner: ic_cdk::caller(),
+        expires_at: ic_cdk::api::time() + seconds * NANOS_PER_SECOND,
+    };
+    LEASES.with(|leases| leases.borrow_mut().insert(key(&components), lease.clone()));
+    Ok(lease)
+}
+
+/// Releases the caller's lease on `path`.
+#[update]
+fn unlock(path: String) -> FsResult<()> {
+    let components = with_fs(|fs| canonical(fs, &path))?;
+    match lease(&components) {
+        Some(lease) if lease.owner == ic_cdk::caller() => {
+            LEASES.with(|leases| leases.borrow_mut().remove(&key(&components)));
+            Ok(())
+        }
+        Some(_) => Err(FsError::PermissionDenied),
+        None => Err(FsError::NotFound),
+    }
+}
+
+/// The lease currently held on `path`, if any. Needs `Read` on it.
+#[query]
+fn lease_info(path: String) -> FsResult<Option<Lease>> {
+    let components = with_fs(|fs| {
+        let components = canonical(fs, &path)?;
+        acl::check(fs, &components, Permission::Read)?;
+        Ok(components)
+    })?;
+    Ok(lease(&components))
+}
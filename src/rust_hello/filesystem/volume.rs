@@ -0,0 +1,314 @@
+use std::{
+    convert::TryInto,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::stable::{stable64_grow, stable64_size};
+use ic_cdk_macros::{query, update};
+
+use super::{
+    acl,
+    fat::{self, BootSector},
+    with_fs, with_unmounted, FsError, FsResult, STABLE_MEMORY,
+};
+
+pub const WASM_PAGE_SIZE: u64 = 64 * 1024;
+const SECTOR_SIZE: u64 = 512;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl From<fatfs::FatType> for FatType {
+    fn from(fat_type: fatfs::FatType) -> Self {
+        match fat_type {
+            fatfs::FatType::Fat12 => FatType::Fat12,
+            fatfs::FatType::Fat16 => FatType::Fat16,
+            fatfs::FatType::Fat32 => FatType::Fat32,
+        }
+    }
+}
+
+impl From<FatType> for fatfs::FatType {
+    fn from(fat_type: FatType) -> Self {
+        match fat_type {
+            FatType::Fat12 => fatfs::FatType::Fat12,
+            FatType::Fat16 => fatfs::FatType::Fat16,
+            FatType::Fat32 => fatfs::FatType::Fat32,
+        }
+    }
+}
+
+/// How `init_volume` formats the volume. Unset fields keep the defaults: a
+/// volume as large as the current wasm heap, with FAT type and cluster size
+/// picked by fatfs for that size.
+///
+/// `max_size_bytes` sizes the allocation tables for a larger volume, so that
+/// `grow_volume` can later extend the volume up to that size. It has to stay
+/// within the FAT type chosen for the initial size.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct VolumeOptions {
+    pub size_bytes: Option<u64>,
+    pub size_pages: Option<u64>,
+    pub max_size_bytes: Option<u64>,
+    pub fat_type: Option<FatType>,
+    pub bytes_per_cluster: Option<u32>,
+    pub volume_label: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct VolumeInfo {
+    pub fat_type: FatType,
+    pub cluster_size: u32,
+    pub total_clusters: u32,
+    pub free_clusters: u32,
+    pub volume_label: String,
+    pub volume_id: u32,
+    pub stable_memory_pages: u64,
+}
+
+/// Reports how the volume was formatted and how much of it is in use.
+/// Sizes are in clusters of `cluster_size` bytes.
+#[query]
+fn volume_info() -> FsResult<VolumeInfo> {
+    with_fs(|fs| {
+        let stats = fs.stats()?;
+
+        Ok(VolumeInfo {
+            fat_type: fs.fat_type().into(),
+            cluster_size: stats.cluster_size(),
+            total_clusters: stats.total_clusters(),
+            free_clusters: stats.free_clusters(),
+            volume_label: fs.volume_label().trim_end().to_string(),
+            volume_id: fs.volume_id(),
+            stable_memory_pages: stable64_size(),
+        })
+    })
+}
+
+fn default_volume_pages() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    let memory_pages = core::arch::wasm32::memory_size(0) as u64;
+
+    #[cfg(not(target_arch = "wasm32"))]
+    let memory_pages = 19;
+
+    memory_pages
+}
+
+fn volume_label(label: &str) -> FsResult<[u8; 11]> {
+    if label.len() > 11 || !label.is_ascii() {
+        return Err(FsError::InvalidArgument(format!(
+            "Volume label must be at most 11 ASCII characters: {}",
+            label
+        )));
+    }
+
+    let mut bytes = [b' '; 11];
+    for (byte, c) in bytes.iter_mut().zip(label.bytes()) {
+        *byte = c.to_ascii_uppercase();
+    }
+    Ok(bytes)
+}
+
+fn sectors(bytes: u64) -> FsResult<u32> {
+    (bytes / SECTOR_SIZE)
+        .try_into()
+        .map_err(|_| FsError::InvalidArgument(format!("Volume too large: {} bytes", bytes)))
+}
+
+pub fn ensure_stable_pages(pages: u64) -> FsResult<()> {
+    let current_pages = stable64_size();
+    if pages > current_pages {
+        stable64_grow(pages - current_pages).map_err(|_| FsError::NoSpace)?;
+    }
+    Ok(())
+}
+
+fn format_options(
+    options: &VolumeOptions,
+    total_sectors: u32,
+) -> FsResult<fatfs::FormatVolumeOptions> {
+    let mut format_options = fatfs::FormatVolumeOptions::new().total_sectors(total_sectors);
+    if let Some(fat_type) = options.fat_type {
+        format_options = format_options.fat_type(fat_type.into());
+    }
+    if let Some(bytes_per_cluster) = options.bytes_per_cluster {
+        if !bytes_per_cluster.is_power_of_two() || !(512..=65536).contains(&bytes_per_cluster) {
+            return Err(FsError::InvalidArgument(format!(
+                "Invalid cluster size: {}",
+                bytes_per_cluster
+            )));
+        }
+        format_options = format_options.bytes_per_cluster(bytes_per_cluster);
+    }
+    if let Some(label) = &options.volume_label {
+        format_options = format_options.volume_label(volume_label(label)?);
+    }
+    Ok(format_options)
+}
+
+// Stands in for stable memory when formatting is tried out first: keeps the
+// boot sector and drops everything written past it.
+struct BootSectorProbe {
+    sector: [u8; SECTOR_SIZE as usize],
+    position: u64,
+    len: u64,
+}
+
+impl Read for BootSectorProbe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        for (index, byte) in buf.iter_mut().enumerate() {
+            *byte = *self
+                .sector
+                .get((self.position + index as u64) as usize)
+                .unwrap_or(&0);
+        }
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+}
+
+impl Write for BootSectorProbe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for (index, byte) in buf.iter().enumerate() {
+            if let Some(stored) = self.sector.get_mut((self.position + index as u64) as usize) {
+                *stored = *byte;
+            }
+        }
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for BootSectorProbe {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => checked_offset(self.position, delta),
+            SeekFrom::End(delta) => checked_offset(self.len, delta),
+        };
+        self.position =
+            position.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek"))?;
+        Ok(self.position)
+    }
+}
+
+fn checked_offset(base: u64, delta: i64) -> Option<u64> {
+    if delta < 0 {
+        base.checked_sub(delta.unsigned_abs())
+    } else {
+        base.checked_add(delta as u64)
+    }
+}
+
+fn format_volume(options: VolumeOptions) -> FsResult<()> {
+    let size = match (options.size_bytes, options.size_pages) {
+        (Some(bytes), _) => bytes,
+        (None, Some(pages)) => pages.checked_mul(WASM_PAGE_SIZE).ok_or_else(|| {
+            FsError::InvalidArgument(format!("Volume too large: {} pages", pages))
+        })?,
+        (None, None) => default_volume_pages() * WASM_PAGE_SIZE,
+    };
+    let total_sectors = sectors(size)?;
+    let max_sectors = sectors(options.max_size_bytes.unwrap_or(size).max(size))?;
+
+    // Lay the volume out on a probe first, so that options fatfs or the
+    // shrinking below reject leave the current volume alone.
+    let mut probe = BootSectorProbe {
+        sector: [0; SECTOR_SIZE as usize],
+        position: 0,
+        len: u64::from(max_sectors) * SECTOR_SIZE,
+    };
+    fatfs::format_volume(
+        &mut fatfs::StdIoWrapper::from(&mut probe),
+        format_options(&options, max_sectors)?,
+    )?;
+    let boot_sector = BootSector::parse(&probe.sector)?;
+
+    // The tables are laid out for the maximum size, the volume itself is
+    // then shrunk down to the space that is actually there.
+    let clusters = total_sectors.saturating_sub(boot_sector.first_data_sector())
+        / boot_sector.sectors_per_cluster;
+    if max_sectors > total_sectors {
+        fat::check_resize(&boot_sector, clusters).map_err(|_| {
+            FsError::InvalidArgument(format!(
+                "A {} byte volume doesn't fit the FAT type needed for {} bytes",
+                size,
+                u64::from(max_sectors) * SECTOR_SIZE
+            ))
+        })?;
+    }
+
+    with_unmounted(|| {
+        ensure_stable_pages((size + WASM_PAGE_SIZE - 1) / WASM_PAGE_SIZE)?;
+
+        STABLE_MEMORY.with(|stable_memory| {
+            fatfs::format_volume(
+                &mut fatfs::StdIoWrapper::from(*stable_memory.borrow()),
+                format_options(&options, max_sectors)?,
+            )
+            .map_err(FsError::from)
+        })?;
+
+        if max_sectors > total_sectors {
+            fat::resize(&BootSector::read()?, clusters)?;
+        }
+        Ok(())
+    })
+}
+
+#[update]
+fn init_volume(confirm: Option<String>, options: Option<VolumeOptions>) -> FsResult<&'static str> {
+    match confirm {
+        Some(s) if s == "confirm_to_init_volume" => {
+            acl::check_volume()?;
+            format_volume(options.unwrap_or_default())?;
+            // The fresh volume belongs to whoever formatted it.
+            with_fs(acl::claim_root)?;
+            Ok("init_volume completed")
+        }
+        _ => Ok("confirm to init_volume ? (input confirm_to_init_volume to init.)"),
+    }
+}
+
+/// Grows stable memory by `pages` and extends the volume into all unused
+/// stable memory without reformatting. The volume only grows as far as its
+/// allocation tables reach, see `VolumeOptions::max_size_bytes`.
+#[update]
+fn grow_volume(pages: u64) -> FsResult<VolumeInfo> {
+    acl::check_volume()?;
+    with_unmounted(|| {
+        let boot_sector = BootSector::read()?;
+        if boot_sector.max_clusters() <= boot_sector.total_clusters() {
+            return Err(FsError::InvalidArgument(
+                "The volume already fills its allocation tables".to_string(),
+            ));
+        }
+        if pages > 0 {
+            stable64_grow(pages).map_err(|_| FsError::NoSpace)?;
+        }
+
+        let available_sectors = (stable64_size() * WASM_PAGE_SIZE
+            / u64::from(boot_sector.bytes_per_sector))
+        .min(u32::MAX.into()) as u32;
+        let available_clusters = available_sectors.saturating_sub(boot_sector.first_data_sector())
+            / boot_sector.sectors_per_cluster;
+        let clusters = available_clusters.min(boot_sector.max_clusters());
+
+        if clusters > boot_sector.total_clusters() {
+            fat::resize(&boot_sector, clusters)?;
+        }
+        Ok(())
+    })?;
+
+    volume_info()
+}
@@ -0,0 +1,667 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    io::{self, Cursor, Read, Write},
+    rc::Rc,
+};
+
+use candid::{CandidType, Deserialize, Principal};
+use fatfs::{Seek, SeekFrom};
+use ic_cdk_macros::update;
+use serde_bytes::ByteBuf;
+use zip::{result::ZipError, write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use super::{
+    acl::{self, Permission},
+    canonical, canonicalize,
+    download::{self, DownloadInfo, MAX_DOWNLOAD_SIZE, MAX_HELD_BYTES},
+    find_entry, home, open_dir_components, open_file, path, record,
+    tar::{self, extract, extract_part, open_or_create_dir, sorted_children, SkippedEntry},
+    timestamp,
+    upload::{self, UploadTarget},
+    with_fs, Change, Dir, FileSystem, FsError, FsResult,
+};
+
+// Work a single call may do before it returns and asks to be continued, so
+// big trees and files don't run into the instruction limit.
+const ENTRY_BUDGET: u64 = 10_000;
+const BYTE_BUDGET: u64 = 32 * 1024 * 1024;
+
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+// Exports a caller may have running at once.
+const MAX_EXPORTS: usize = 4;
+
+// Exports not continued for this long are dropped when the next one starts.
+const EXPORT_TIMEOUT: u64 = 60 * 60 * 1_000_000_000;
+
+// A deflated entry that takes several calls is inflated from its start
+// again by each of them, which has to stay within the instruction limit.
+const MAX_ENTRY_SIZE: u64 = 1024 * 1024 * 1024;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ZipMethod {
+    Stored,
+    Deflated,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ZipExport {
+    pub job: u64,
+    pub entries: u64,
+    pub complete: bool,
+    pub download: Option<DownloadInfo>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct ZipImportReport {
+    pub files: u64,
+    pub directories: u64,
+    pub skipped: Vec<SkippedEntry>,
+    pub complete: bool,
+}
+
+// Where the archive goes while it is built: bytes since the last flush stay
+// here, then go to the spool file. The writer seeks back to fill in a
+// member's header once its data is written, and those writes may land in
+// bytes that were flushed already, so they are kept as patches.
+#[derive(Default)]
+struct Spool {
+    // How many bytes were flushed, which is where `buf` starts.
+    flushed: u64,
+    position: u64,
+    buf: Vec<u8>,
+    patches: Vec<(u64, Vec<u8>)>,
+}
+
+#[derive(Clone, Default)]
+struct SpoolWriter(Rc<RefCell<Spool>>);
+
+impl Write for SpoolWriter {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        let mut spool = self.0.borrow_mut();
+        let position = spool.position;
+        if position < spool.flushed {
+            let len = bytes.len().min((spool.flushed - position) as usize);
+            spool.patches.push((position, bytes[..len].to_vec()));
+            spool.position += len as u64;
+            return Ok(len);
+        }
+
+        let start = (position - spool.flushed) as usize;
+        let end = start + bytes.len();
+        if spool.buf.len() < end {
+            spool.buf.resize(end, 0);
+        }
+        spool.buf[start..end].copy_from_slice(bytes);
+        spool.position += bytes.len() as u64;
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl io::Seek for SpoolWriter {
+    fn seek(&mut self, to: io::SeekFrom) -> io::Result<u64> {
+        let mut spool = self.0.borrow_mut();
+        let end = spool.flushed + spool.buf.len() as u64;
+        let position = match to {
+            io::SeekFrom::Start(offset) => Some(offset),
+            io::SeekFrom::End(offset) => checked_offset(end, offset),
+            io::SeekFrom::Current(offset) => checked_offset(spool.position, offset),
+        };
+        spool.position = position
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Seek out of range"))?;
+        Ok(spool.position)
+    }
+}
+
+fn checked_offset(base: u64, offset: i64) -> Option<u64> {
+    if offset < 0 {
+        base.checked_sub(offset.unsigned_abs())
+    } else {
+        base.checked_add(offset as u64)
+    }
+}
+
+impl SpoolWriter {
+    fn size(&self) -> u64 {
+        let spool = self.0.borrow();
+        spool.flushed + spool.buf.len() as u64
+    }
+
+    // Appends what was written since the last flush to spool file `id` and
+    // applies the patches.
+    fn flush_to(&self, fs: &FileSystem, id: u64) -> FsResult<()> {
+        let mut spool = self.0.borrow_mut();
+        let mut file = download::spool_file(fs, id)?;
+        file.seek(SeekFrom::Start(spool.flushed))?;
+        file.write_all(&spool.buf)?;
+        for (offset, bytes) in &spool.patches {
+            file.seek(SeekFrom::Start(*offset))?;
+            file.write_all(bytes)?;
+        }
+        file.flush()?;
+
+        let flushed = spool.buf.len() as u64;
+        spool.flushed += flushed;
+        spool.buf.clear();
+        spool.patches.clear();
+        Ok(())
+    }
+}
+
+struct ExportJob {
+    owner: Principal,
+    // When a call last continued the export, in nanoseconds since the epoch.
+    last_used: u64,
+    // The download the archive becomes, spooled to the volume as it grows.
+    download: u64,
+    spool: SpoolWriter,
+    writer: ZipWriter<SpoolWriter>,
+    method: CompressionMethod,
+    // Components of the exported directory, member names are relative to it.
+    root_len: usize,
+    // Entries still to visit, the next one last.
+    pending: Vec<Vec<String>>,
+    // A file that is partly written, and how far.
+    current: Option<(Vec<String>, u64)>,
+    entries: u64,
+}
+
+thread_local! {
+    static EXPORTS: RefCell<BTreeMap<u64, ExportJob>> = RefCell::default();
+    static NEXT_EXPORT_ID: RefCell<u64> = RefCell::new(0);
+}
+
+fn options(method: CompressionMethod, modified: fatfs::DateTime, mode: u32) -> FileOptions {
+    let (date, time) = (modified.date, modified.time);
+    let modified = zip::DateTime::from_date_and_time(
+        date.year,
+        date.month as u8,
+        date.day as u8,
+        time.hour as u8,
+        time.min as u8,
+        time.sec as u8,
+    )
+    .unwrap_or_default();
+
+    FileOptions::default()
+        .compression_method(method)
+        .last_modified_time(modified)
+        .unix_permissions(mode)
+}
+
+impl ExportJob {
+    fn member_name(&self, components: &[String]) -> String {
+        components[self.root_len..].join("/")
+    }
+
+    // Writes more of the current file. Returns false when the budget ran out first.
+    fn copy_current(&mut self, fs: &FileSystem, budget: &mut u64) -> FsResult<bool> {
+        let (components, offset) = match self.current.take() {
+            Some(current) => current,
+            None => return Ok(true),
+        };
+        let (name, parent) = components.split_last().ok_or(FsError::InvalidPath)?;
+        let mut file = open_file(&open_dir_components(fs, parent)?, name)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut buf = vec![0; COPY_BUFFER_SIZE];
+        let mut copied = 0;
+        while *budget > 0 {
+            let read = file.read(&mut buf)?;
+            if read == 0 {
+                return Ok(true);
+            }
+            self.writer.write_all(&buf[..read])?;
+            copied += read as u64;
+            *budget = budget.saturating_sub(read as u64);
+        }
+
+        self.current = Some((components, offset + copied));
+        Ok(false)
+    }
+
+    // Adds entries until everything is written or the budget runs out, with
+    // the archive staying within `limit` bytes. Returns whether it is complete.
+    fn run(&mut self, fs: &FileSystem, limit: u64) -> FsResult<bool> {
+        let mut budget = BYTE_BUDGET;
+        let mut entry_budget = ENTRY_BUDGET;
+        loop {
+            if !self.copy_current(fs, &mut budget)? {
+                return Ok(false);
+            }
+            if self.spool.size() > limit {
+                return Err(FsError::NoSpace);
+            }
+            if entry_budget == 0 || budget == 0 {
+                return Ok(self.pending.is_empty());
+            }
+
+            let components = match self.pending.pop() {
+                Some(components) => components,
+                None => return Ok(true),
+            };
+            entry_budget -= 1;
+
+            let (name, parent) = components.split_last().ok_or(FsError::InvalidPath)?;
+            // Entries removed since the export started are left out.
+            let entry = match find_entry(&open_dir_components(fs, parent)?, name)? {
+                Some(entry) => entry,
+                None => continue,
+            };
+
+            let member_name = self.member_name(&components);
+            if entry.is_dir() {
+                let options = options(self.method, entry.modified(), 0o755);
+                self.writer
+                    .add_directory(member_name, options)
+                    .map_err(|error| FsError::Io(error.to_string()))?;
+                let mut children = sorted_children(&entry.to_dir(), &components)?;
+                children.reverse();
+                self.pending.extend(children);
+            } else {
+                let options = options(self.method, entry.modified(), 0o644);
+                self.writer
+                    .start_file(member_name, options)
+                    .map_err(|error| FsError::Io(error.to_string()))?;
+                self.current = Some((components, 0));
+            }
+            self.entries += 1;
+        }
+    }
+}
+
+/// Bytes the zip exports still being built hold.
+pub fn exporting_bytes() -> u64 {
+    EXPORTS.with(|exports| exports.borrow().values().map(|job| job.spool.size()).sum())
+}
+
+// Runs export `id` for another slice, moves what it added to the spool file
+// and hands out the archive once it is done. Together with the other exports
+// and the open downloads it has to stay within `MAX_HELD_BYTES`.
+fn continue_export(id: u64) -> FsResult<ZipExport> {
+    EXPORTS.with(|exports| {
+        let mut exports = exports.borrow_mut();
+        let others = exports
+            .iter()
+            .filter(|(other, _)| **other != id)
+            .map(|(_, job)| job.spool.size())
+            .sum::<u64>()
+            + tar::exporting_bytes();
+        let limit = MAX_HELD_BYTES
+            .saturating_sub(others + download::held_bytes())
+            .min(MAX_DOWNLOAD_SIZE);
+        let job = exports
+            .get_mut(&id)
+            .filter(|job| job.owner == ic_cdk::caller())
+            .ok_or(FsError::NotFound)?;
+        job.last_used = ic_cdk::api::time();
+
+        let result = with_fs(|fs| {
+            let complete = job.run(fs, limit)?;
+            if complete {
+                job.writer
+                    .finish()
+                    .map_err(|error| FsError::Io(error.to_string()))?;
+            }
+            job.spool.flush_to(fs, job.download)?;
+            Ok(complete)
+        });
+        let complete = match result {
+            Ok(complete) => complete,
+            Err(error) => {
+                let job = exports.remove(&id).unwrap();
+                let _ = with_fs(|fs| download::remove_spool(fs, job.download));
+                return Err(error);
+            }
+        };
+        let entries = job.entries;
+
+        let download = if complete {
+            let job = exports.remove(&id).unwrap();
+            Some(download::finish_spool(job.download, job.spool.size()))
+        } else {
+            None
+        };
+
+        Ok(ZipExport {
+            job: id,
+            entries,
+            complete,
+            download,
+        })
+    })
+}
+
+/// Starts packing `path` into a zip archive. Member names are relative to
+/// `path`; a single file is archived under its own name. Large trees take
+/// several calls: until `complete` is set, continue with `continue_zip_export`.
+/// The archive is spooled to the volume as it grows and handed out as a
+/// download once finished. Exports not continued for an hour are dropped.
+#[update]
+fn export_zip(path: String, method: ZipMethod) -> FsResult<ZipExport> {
+    let components = with_fs(|fs| {
+        let components = canonical(fs, &path)?;
+        acl::check_tree(fs, &components, Permission::Read)?;
+        Ok(components)
+    })?;
+    let (root_len, pending) = with_fs(|fs| match components.split_last() {
+        Some((name, parent)) => {
+            let entry =
+                find_entry(&open_dir_components(fs, parent)?, name)?.ok_or(FsError::NotFound)?;
+            if entry.is_dir() {
+                let mut children = sorted_children(&entry.to_dir(), &components)?;
+                children.reverse();
+                Ok((components.len(), children))
+            } else {
+                Ok((parent.len(), vec![components.clone()]))
+            }
+        }
+        None => {
+            let mut children = sorted_children(&fs.root_dir(), &[])?;
+            children.reverse();
+            Ok((0, children))
+        }
+    })?;
+
+    let owner = ic_cdk::caller();
+    let now = ic_cdk::api::time();
+    EXPORTS.with(|exports| {
+        let mut exports = exports.borrow_mut();
+        exports.retain(|_, job| {
+            let live = job.last_used.saturating_add(EXPORT_TIMEOUT) > now;
+            if !live {
+                download::drop_spool(job.download);
+            }
+            live
+        });
+        if exports.values().filter(|job| job.owner == owner).count() >= MAX_EXPORTS {
+            return Err(FsError::InvalidArgument(format!(
+                "At most {} zip exports can run at once",
+                MAX_EXPORTS
+            )));
+        }
+        Ok(())
+    })?;
+    let download = with_fs(download::create_spool)?;
+
+    let id = NEXT_EXPORT_ID.with(|next| {
+        let mut next = next.borrow_mut();
+        *next += 1;
+        *next
+    });
+    let spool = SpoolWriter::default();
+    EXPORTS.with(|exports| {
+        exports.borrow_mut().insert(
+            id,
+            ExportJob {
+                owner,
+                last_used: now,
+                download,
+                spool: spool.clone(),
+                writer: ZipWriter::new(spool),
+                method: match method {
+                    ZipMethod::Stored => CompressionMethod::Stored,
+                    ZipMethod::Deflated => CompressionMethod::Deflated,
+                },
+                root_len,
+                pending,
+                current: None,
+                entries: 0,
+            },
+        )
+    });
+
+    continue_export(id)
+}
+
+#[update]
+fn continue_zip_export(job: u64) -> FsResult<ZipExport> {
+    continue_export(job)
+}
+
+/// Starts staging a zip archive that `import_zip` extracts below `path`.
+/// Chunks go through `put_chunk` like any other upload.
+#[update]
+fn begin_zip_import(path: String) -> FsResult<u64> {
+    let root = with_fs(|fs| {
+        let root = canonical(fs, &path)?;
+        acl::check_tree(fs, &root, Permission::Write)?;
+        Ok(root)
+    })?;
+    upload::begin(UploadTarget::Zip {
+        root,
+        next_entry: 0,
+        written: 0,
+    })
+}
+
+// Reads at most `remaining` bytes and notes whether the entry had more, so an
+// entry inflating beyond its declared size can't fill the volume.
+struct Bounded<R> {
+    inner: R,
+    remaining: u64,
+    overflowed: bool,
+}
+
+impl<R: Read> Read for Bounded<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.remaining == 0 {
+            let mut probe = [0];
+            self.overflowed = self.inner.read(&mut probe)? > 0;
+            return Ok(0);
+        }
+
+        let len = buf.len().min(self.remaining as usize);
+        let read = self.inner.read(&mut buf[..len])?;
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+fn invalid_archive(error: ZipError) -> FsError {
+    FsError::InvalidArgument(format!("Invalid zip archive: {}", error))
+}
+
+fn modified(file: &zip::read::ZipFile) -> fatfs::DateTime {
+    let modified = file.last_modified();
+    timestamp::from_civil(
+        modified.year(),
+        modified.month().into(),
+        modified.day().into(),
+        modified.hour().into(),
+        modified.minute().into(),
+        modified.second().into(),
+    )
+    .unwrap_or_else(|| timestamp::from_unix_secs(ic_cdk::api::time() / 1_000_000_000))
+}
+
+// Extracts entries from `next_entry` on, skipping the first `written` bytes
+// of that entry's data, until the archive or the budget is exhausted. An
+// entry cut off by the budget is continued by the next call.
+fn unpack(
+    archive: &mut ZipArchive<Cursor<&[u8]>>,
+    next_entry: &mut usize,
+    written: &mut u64,
+    fs: &FileSystem,
+    root: &Dir,
+    target: &[String],
+    changes: &mut Vec<Change>,
+) -> FsResult<ZipImportReport> {
+    let mut report = ZipImportReport::default();
+    let mut budget = BYTE_BUDGET;
+    let mut entry_budget = ENTRY_BUDGET;
+    while *next_entry < archive.len() && budget > 0 && entry_budget > 0 {
+        let index = *next_entry;
+        *next_entry += 1;
+        entry_budget -= 1;
+
+        let name = archive
+            .by_index_raw(index)
+            .map_err(invalid_archive)?
+            .name()
+            .to_string();
+        let file = match archive.by_index(index) {
+            Ok(file) => file,
+            // Encrypted entries and compression methods other than deflate.
+            Err(ZipError::UnsupportedArchive(reason)) => {
+                report.skipped.push(SkippedEntry {
+                    path: name,
+                    reason: reason.to_string(),
+                });
+                continue;
+            }
+            Err(error) => return Err(invalid_archive(error)),
+        };
+        let declared = file.size();
+        if declared > MAX_ENTRY_SIZE {
+            report.skipped.push(SkippedEntry {
+                path: name,
+                reason: format!("Larger than {} bytes", MAX_ENTRY_SIZE),
+            });
+            continue;
+        }
+        let is_dir = file.is_dir();
+
+        // Entries always land below the target, `..` can't climb out of it.
+        let components = match path::normalize(&name) {
+            Ok(components) if components.is_empty() => continue,
+            Ok(components) => components,
+            Err(_) => {
+                report.skipped.push(SkippedEntry {
+                    path: name,
+                    reason: "Invalid entry path".to_string(),
+                });
+                continue;
+            }
+        };
+
+        let mtime = modified(&file);
+        let mut contents = Bounded {
+            inner: file,
+            remaining: declared,
+            overflowed: false,
+        };
+        let mut full_path = target.to_vec();
+        full_path.extend(components.iter().cloned());
+        let full_path = canonicalize(fs, full_path)?;
+        let result = if is_dir {
+            home::reserve_dir(fs, &full_path)
+                .and_then(|()| extract(root, &components, true, io::empty(), mtime))
+        } else {
+            // What earlier calls extracted is inflated again and dropped.
+            io::copy(&mut (&mut contents).take(*written), &mut io::sink())?;
+            let len = (declared - *written).min(budget);
+            let mut data = vec![];
+            (&mut contents).take(len).read_to_end(&mut data)?;
+            // An entry that ends early is done as well.
+            let last = contents.remaining == 0 || (data.len() as u64) < len;
+            if contents.remaining == 0 {
+                // Reads nothing, but notes whether the entry has more.
+                contents.read(&mut [0])?;
+            }
+            let offset = *written;
+            budget = budget.saturating_sub(data.len() as u64);
+            let result = home::reserve_file(fs, &full_path, offset + data.len() as u64)
+                .and_then(|()| extract_part(root, &components, offset, &data, last.then(|| mtime)));
+            if result.is_ok() && !last {
+                *written += data.len() as u64;
+                *next_entry = index;
+                changes.push(Change::Written(full_path));
+                return Ok(report);
+            }
+            *written = 0;
+            result
+        };
+        if contents.overflowed {
+            changes.push(Change::Written(full_path));
+            return Err(FsError::InvalidArgument(format!(
+                "Invalid zip archive: {} is larger than declared",
+                name
+            )));
+        }
+        match result {
+            Ok(()) if is_dir => {
+                report.directories += 1;
+                changes.push(Change::Created(full_path));
+            }
+            Ok(()) => {
+                report.files += 1;
+                changes.push(Change::Written(full_path));
+            }
+            Err(
+                error @ (FsError::InvalidPath
+                | FsError::NotADirectory
+                | FsError::IsADirectory
+                | FsError::AlreadyExists),
+            ) => report.skipped.push(SkippedEntry {
+                path: name,
+                reason: format!("{:?}", error),
+            }),
+            Err(error) => return Err(error),
+        }
+    }
+
+    report.complete = *next_entry >= archive.len();
+    Ok(report)
+}
+
+/// Extracts the zip archive staged in upload `id`. Big archives and entries
+/// take several calls, each continuing where the last one stopped, until
+/// `complete` is set and the upload is closed. `sha256` is checked before the first entry
+/// is extracted. Entries FAT can't hold are reported as skipped.
+#[update]
+fn import_zip(id: u64, sha256: Option<ByteBuf>) -> FsResult<ZipImportReport> {
+    let report = upload::with_session(id, |session| {
+        let (target, next_entry, written) = match &mut session.target {
+            UploadTarget::Zip {
+                root,
+                next_entry,
+                written,
+            } => (root, next_entry, written),
+            _ => {
+                return Err(FsError::InvalidArgument(format!(
+                    "Upload {} is not a zip archive",
+                    id
+                )))
+            }
+        };
+        if *next_entry == 0 && *written == 0 {
+            upload::verify_checksum(&session.data, sha256)?;
+        }
+
+        let mut archive =
+            ZipArchive::new(Cursor::new(&session.data[..])).map_err(invalid_archive)?;
+
+        with_fs(|fs| {
+            acl::check_tree(fs, target, Permission::Write)?;
+            home::ensure(fs, target)?;
+            let root = target
+                .iter()
+                .try_fold(fs.root_dir(), |dir, name| open_or_create_dir(&dir, name))?;
+
+            // Whatever was extracted before an error stays, so it is recorded either way.
+            let mut changes = vec![];
+            let result = unpack(
+                &mut archive,
+                next_entry,
+                written,
+                fs,
+                &root,
+                target,
+                &mut changes,
+            );
+            record(fs, &changes);
+            result
+        })
+    })?;
+
+    if report.complete {
+        upload::close(id);
+    }
+    Ok(report)
+}
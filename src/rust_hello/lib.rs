@@ -21,11 +21,22 @@ struct StableStorage {
     vfs_root: serde_bytes::ByteBuf,
 }
 
+#[init]
+fn init() {
+    filesystem::remember_controller();
+}
+
 #[export_name = "canister_pre_upgrade"]
-fn pre_upgrade() {}
+fn pre_upgrade() {
+    // The volume already lives in stable memory, unmounting only flushes it.
+    // A failure here must not block the upgrade.
+    let _ = filesystem::unmount_volume();
+}
 
 #[export_name = "canister_post_upgrade"]
-fn post_upgrade() {}
+fn post_upgrade() {
+    filesystem::remember_controller();
+}
 
 #[query]
 fn http_query(request: HttpQuery) -> HttpQueryReponse {